@@ -1,7 +1,13 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
 use serde::{Deserialize};
 use serde_derive::Serialize;
+use crate::cid::{Cid, CidVersion};
+use crate::errors::ApiError;
+use crate::progress::ProgressReporter;
+use crate::utils;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum JobStatus {
     /// Pinata is running preliminary validations on your pin request.
@@ -22,6 +28,24 @@ pub enum JobStatus {
     InvalidObject,
     /// You provided a host node that was either invalid or unreachable.
     BadHostNode,
+    /// The pinning service accepted the request and has not started on it yet.
+    Queued,
+    /// The pinning service is retrieving and pinning the content.
+    Pinning,
+    /// The content is pinned.
+    Pinned,
+    /// The pinning service gave up on the request.
+    Failed,
+}
+
+/// Parses the snake case name of a status, e.g. `pinned` or `over_free_limit`
+impl FromStr for JobStatus {
+    type Err = ApiError;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(status.trim().to_string()))
+            .map_err(|_| ApiError::GenericError(format!("Unknown pin status {}, use e.g. pinned, pinning, queued or failed", status)))
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -30,49 +54,245 @@ pub struct PinnedObject {
     pub ipfs_hash: String,
     pub pin_size: u64,
     pub timestamp: String,
+    /// Whether `ipfs_hash` matches the cid computed locally, `None` when it wasn't checked
+    #[serde(skip)]
+    pub verified: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
-// #[serde(rename_all = "PascalCase")]
+#[serde(rename_all = "camelCase")]
 pub struct PinnedResult {
     pub cid: String,
-    pub carCid: String
+    #[allow(unused)]
+    pub car_cid: Option<String>
+}
+
+/// Name and tags stored with a pin so it can be found again, each provider keeps them in its
+/// own way and leaves out what it has no place for
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PinMetadata {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl PinMetadata {
+    pub fn new() -> Self {
+        PinMetadata::default()
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds a tag written as `key=value`
+    pub fn with_tag_pair(self, tag: &str) -> Result<Self, ApiError> {
+        match tag.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(self.with_tag(key.trim(), value.trim())),
+            _ => Err(ApiError::GenericError(format!("Tag {} is not written as key=value", tag))),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.tags.is_empty()
+    }
+
+    /// Tells the user `provider` drops the name and tags, for providers with no place for them
+    pub(crate) fn warn_dropped(&self, provider: &str) {
+        if !self.is_empty() {
+            println!("[{}] names and tags are not supported and were not stored", provider);
+        }
+    }
+
+    /// Tells the user `provider` drops the tags, for providers that can only store a name
+    pub(crate) fn warn_tags_dropped(&self, provider: &str) {
+        if !self.tags.is_empty() {
+            println!("[{}] tags are not supported and were not stored", provider);
+        }
+    }
+}
+
+/// Providers that read the options set with `PinOptions::with_extension`
+const EXTENSION_PROVIDERS: &[&str] = &["pinata", "kubo"];
+
+/// How a provider should store an upload. Providers send what they support and warn about
+/// the options they cannot honour
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PinOptions {
+    /// Version of the cid the provider answers with
+    pub cid_version: Option<CidVersion>,
+    /// Wraps the upload in a directory so a single file keeps its name under the root
+    pub wrap_with_directory: bool,
+    /// Copies to keep in each region, keyed by the provider's region id, e.g. `FRA1`
+    pub replication: BTreeMap<String, u32>,
+    /// Options only one provider understands, keyed by registry name then option name,
+    /// sent to that provider as given
+    pub extensions: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+impl PinOptions {
+    pub fn new() -> Self {
+        PinOptions::default()
+    }
+
+    pub fn with_cid_version(mut self, cid_version: CidVersion) -> Self {
+        self.cid_version = Some(cid_version);
+        self
+    }
+
+    pub fn with_wrap_with_directory(mut self, wrap_with_directory: bool) -> Self {
+        self.wrap_with_directory = wrap_with_directory;
+        self
+    }
+
+    pub fn with_replication<S: Into<String>>(mut self, region: S, copies: u32) -> Self {
+        self.replication.insert(region.into(), copies);
+        self
+    }
+
+    /// Adds an option for `provider` only, e.g. `pinata` and `groupId`
+    pub fn with_extension<P: Into<String>, K: Into<String>>(mut self, provider: P, key: K, value: serde_json::Value) -> Self {
+        self.extensions.entry(provider.into()).or_default().insert(key.into(), value);
+        self
+    }
+
+    /// Options set for `provider` only
+    pub fn extension(&self, provider: &str) -> Option<&serde_json::Map<String, serde_json::Value>> {
+        self.extensions.get(provider)
+    }
+
+    /// Tells the user which of the common options `provider` ignores, `supported` lists the ones it honours
+    pub(crate) fn warn_unsupported(&self, provider: &str, supported: &[&str]) {
+        let mut requested = vec![];
+        if self.cid_version.is_some() {
+            requested.push("cid_version");
+        }
+        if self.wrap_with_directory {
+            requested.push("wrap_with_directory");
+        }
+        if !self.replication.is_empty() {
+            requested.push("replication");
+        }
+        let ignored = requested.into_iter().filter(|option| !supported.contains(option)).collect::<Vec<&str>>();
+        if !ignored.is_empty() {
+            println!("[{}] does not support {} and ignored it", provider, ignored.join(", "));
+        }
+    }
+
+    /// Tells the user about every provider option no provider will read, because its provider
+    /// is not among the `selected` registry names or takes no options
+    pub(crate) fn warn_unread_extensions(&self, selected: &[&str]) {
+        for (provider, options) in self.extensions.iter() {
+            let reason = if !selected.contains(&provider.as_str()) {
+                "is not one of the selected providers"
+            } else if !EXTENSION_PROVIDERS.contains(&provider.as_str()) {
+                "takes no provider options"
+            } else {
+                continue;
+            };
+            for key in options.keys() {
+                println!("Provider option {}.{} is ignored, {} {}", provider, key, provider, reason);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PinByFile {
     pub(crate) files: Vec<String>,
+    #[serde(default)]
+    pub(crate) metadata: PinMetadata,
+    #[serde(skip)]
+    pub(crate) options: PinOptions,
+    /// Told about the bytes sent as the files are uploaded
+    #[serde(skip)]
+    pub(crate) progress: Option<ProgressReporter>,
 }
 
-// impl PinByFile {
-//     pub fn new<S: Into<String>>(path: S) -> Self {
-//         PinByFile { files: vec![path.into()] }
-//     }
-// }
+impl PinByFile {
+    pub fn new(files: Vec<String>) -> Self {
+        PinByFile { files, metadata: PinMetadata::default(), options: PinOptions::default(), progress: None }
+    }
+
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_options(mut self, options: PinOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PinByCar {
+    /// Path to a CARv1 archive
+    pub(crate) file: String,
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PinByJson {
     pub(crate) file: String,
+    #[serde(default)]
+    pub(crate) metadata: PinMetadata,
+    #[serde(skip)]
+    pub(crate) options: PinOptions,
+}
+
+impl PinByJson {
+    pub fn new<S: Into<String>>(file: S) -> Self {
+        PinByJson { file: file.into(), metadata: PinMetadata::default(), options: PinOptions::default() }
+    }
+
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_options(mut self, options: PinOptions) -> Self {
+        self.options = options;
+        self
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PinByHash {
-    pub(crate) hash_to_pin: String,
+    pub(crate) hash_to_pin: Cid,
+    /// Sent by each provider in its own way, never as part of this body
+    #[serde(skip)]
+    pub(crate) metadata: PinMetadata,
+}
+
+impl PinByHash {
+    pub fn new(hash_to_pin: Cid) -> Self {
+        PinByHash { hash_to_pin, metadata: PinMetadata::default() }
+    }
+
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UnPin {
-    pub(crate) cid: String,
+    pub(crate) cid: Cid,
 }
 
-// impl PinByJson {
-//     pub fn new<S: Into<String>>(path: S) -> Self {
-//         PinByJson { file: path.into() }
-//     }
-// }
-
 #[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PinByHashResult {
@@ -82,3 +302,99 @@ pub struct PinByHashResult {
     pub name: Option<String>,
 }
 
+
+/// A pin as `list_pins` reports it, the same for every provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinRecord {
+    pub cid: String,
+    /// Bytes the provider counts for the pin, `None` when it does not say
+    pub size: Option<u64>,
+    /// When the pin was created as an ISO 8601 timestamp, `None` when the provider does not say
+    pub date: Option<String>,
+    pub status: JobStatus,
+    /// Name and tags the pin was stored with, as far as the provider keeps them
+    pub metadata: PinMetadata,
+}
+
+impl PinRecord {
+    pub fn new<S: Into<String>>(cid: S, status: JobStatus) -> Self {
+        PinRecord { cid: cid.into(), size: None, date: None, status, metadata: PinMetadata::default() }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.metadata.name.as_deref()
+    }
+}
+
+/// Which pins `list_pins` returns. Providers send their api what it can filter on and check
+/// the rest on the pins it answers with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PinFilter {
+    /// Part of the name, compared ignoring case
+    pub name: Option<String>,
+    /// Tags the pin must have, with these values
+    pub tags: BTreeMap<String, String>,
+    /// Pins created on or after this `YYYY-MM-DD` date or ISO 8601 timestamp
+    pub after: Option<String>,
+    /// Pins created before this `YYYY-MM-DD` date or ISO 8601 timestamp
+    pub before: Option<String>,
+    pub status: Option<JobStatus>,
+}
+
+impl PinFilter {
+    pub fn new() -> Self {
+        PinFilter::default()
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_after<S: Into<String>>(mut self, after: S) -> Self {
+        self.after = Some(after.into());
+        self
+    }
+
+    pub fn with_before<S: Into<String>>(mut self, before: S) -> Self {
+        self.before = Some(before.into());
+        self
+    }
+
+    pub fn with_status(mut self, status: JobStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Whether `record` passes every part of the filter. Dates are compared by the instant they
+    /// stand for, and a pin without a date is outside any range
+    pub fn matches(&self, record: &PinRecord) -> bool {
+        if let Some(name) = self.name.as_deref() {
+            match record.name() {
+                Some(pinned) if pinned.to_lowercase().contains(&name.to_lowercase()) => {}
+                _ => return false,
+            }
+        }
+        if self.tags.iter().any(|(key, value)| record.metadata.tags.get(key) != Some(value)) {
+            return false;
+        }
+        if self.after.is_some() || self.before.is_some() {
+            let date = match record.date.as_deref() {
+                Some(date) => date,
+                None => return false,
+            };
+            if self.after.as_deref().map(|after| utils::compare_timestamps(date, after).is_lt()).unwrap_or(false) {
+                return false;
+            }
+            if self.before.as_deref().map(|before| utils::compare_timestamps(date, before).is_ge()).unwrap_or(false) {
+                return false;
+            }
+        }
+        self.status.as_ref().map(|status| *status == record.status).unwrap_or(true)
+    }
+}
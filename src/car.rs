@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cid::{read_varint, write_varint, Cid, CidVersion};
+use crate::errors::ApiError;
+use crate::unixfs::{DagBuilder, DagOptions};
+
+/// Writes a CARv1 archive block by block.
+///
+/// The root is only known once the whole dag is built, so the header is written with a
+/// placeholder root of the right length and patched by `finish`.
+pub struct CarWriter<W: Write + Seek> {
+    out: W,
+    root_offset: u64,
+    root_len: usize,
+    written: HashSet<Cid>,
+    size: u64,
+}
+
+impl<W: Write + Seek> CarWriter<W> {
+    pub fn new(mut out: W, version: CidVersion) -> Result<Self, ApiError> {
+        // v0 cids are a bare sha2-256 multihash, v1 ones add the version and codec varints
+        let root_len = match version {
+            CidVersion::V0 => 34,
+            CidVersion::V1 => 36,
+        };
+        let header = encode_header(&vec![0u8; root_len]);
+        let mut prefix = vec![];
+        write_varint(&mut prefix, header.len() as u64);
+
+        out.write_all(&prefix)?;
+        out.write_all(&header)?;
+
+        let size = (prefix.len() + header.len()) as u64;
+        Ok(CarWriter { out, root_offset: root_offset(root_len), root_len, written: HashSet::new(), size })
+    }
+
+    /// Appends a block, skipping blocks that are already in the archive
+    pub fn put(&mut self, cid: &Cid, block: &[u8]) -> Result<(), ApiError> {
+        if self.written.contains(cid) {
+            return Ok(());
+        }
+        let cid_bytes = cid.to_bytes();
+        let mut prefix = vec![];
+        write_varint(&mut prefix, (cid_bytes.len() + block.len()) as u64);
+
+        self.out.write_all(&prefix)?;
+        self.out.write_all(&cid_bytes)?;
+        self.out.write_all(block)?;
+        self.written.insert(cid.clone());
+        self.size += (prefix.len() + cid_bytes.len() + block.len()) as u64;
+        Ok(())
+    }
+
+    /// Number of bytes written to the archive so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of distinct blocks in the archive
+    pub fn block_count(&self) -> usize {
+        self.written.len()
+    }
+
+    /// Flushes the archive leaving the placeholder root in the header, for archives whose root
+    /// is only known later and written with `patch_root`
+    pub fn close(mut self) -> Result<W, ApiError> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Writes the real root into the header and flushes the archive
+    pub fn finish(mut self, root: &Cid) -> Result<W, ApiError> {
+        let root_bytes = root.to_bytes();
+        if root_bytes.len() != self.root_len {
+            return Err(ApiError::GenericError(format!("Root cid {} does not fit the car header", root)));
+        }
+        self.out.seek(SeekFrom::Start(self.root_offset))?;
+        self.out.write_all(&root_bytes)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Builds the UnixFS dag of `files` and writes it to `out` as a CARv1 archive, returning the root cid
+pub fn write_car<W: Write + Seek>(files: &[String], options: DagOptions, out: W) -> Result<(Cid, W), ApiError> {
+    let mut writer = CarWriter::new(out, options.cid_version)?;
+    let root = DagBuilder::new(options).build(files, &mut |cid, block| writer.put(cid, block))?;
+    let out = writer.finish(&root.cid)?;
+    Ok((root.cid, out))
+}
+
+/// Writes the CARv1 archive of `files` to the file at `output`
+pub fn create_car_file<P: AsRef<Path>>(files: &[String], options: DagOptions, output: P) -> Result<Cid, ApiError> {
+    let file = BufWriter::new(File::create(output)?);
+    let (root, _) = write_car(files, options, file)?;
+    Ok(root)
+}
+
+/// A CARv1 archive holding part of a dag. Every shard of a dag carries the root of the whole dag
+#[derive(Clone, Debug)]
+pub struct CarShard {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Writes the dag of `files` into CARv1 shards of at most `shard_size` bytes inside `dir`.
+///
+/// A block bigger than `shard_size` still gets a shard of its own, so shards should be a
+/// good deal larger than the chunk size. Each shard is closed as soon as it is full and its
+/// root patched in once the whole dag is built.
+pub fn write_car_shards(files: &[String], options: DagOptions, dir: &Path, shard_size: u64) -> Result<(Cid, Vec<CarShard>), ApiError> {
+    let mut shards = vec![];
+    let root = write_shards(files, options, dir, shard_size, None, &mut |shard| {
+        shards.push(shard);
+        Ok(())
+    })?;
+    for shard in shards.iter() {
+        patch_root(&shard.path, &root)?;
+    }
+    Ok((root, shards))
+}
+
+/// Like `write_car_shards` for a dag whose `root` is already known, e.g. from
+/// `DagBuilder::root_cid`. Each shard is complete when it is handed to `on_shard`, which can
+/// upload and remove it before the next one is written
+pub fn stream_car_shards<F>(files: &[String], options: DagOptions, dir: &Path, shard_size: u64, root: &Cid, mut on_shard: F) -> Result<(), ApiError>
+    where F: FnMut(CarShard) -> Result<(), ApiError>
+{
+    let built = write_shards(files, options, dir, shard_size, Some(root), &mut on_shard)?;
+    if built != *root {
+        return Err(ApiError::GenericError(format!("The files changed while they were sharded, root {} became {}", root, built)));
+    }
+    Ok(())
+}
+
+/// Writes the `root` of a closed archive at `path` over its placeholder
+pub fn patch_root(path: &Path, root: &Cid) -> Result<(), ApiError> {
+    let root_bytes = root.to_bytes();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(root_offset(root_bytes.len())))?;
+    file.write_all(&root_bytes)?;
+    Ok(())
+}
+
+/// Builds the dag into shards, handing every shard to `on_shard` once it is closed. Shards
+/// carry `root` when it is given and a placeholder otherwise
+fn write_shards(files: &[String], options: DagOptions, dir: &Path, shard_size: u64, root: Option<&Cid>, on_shard: &mut dyn FnMut(CarShard) -> Result<(), ApiError>) -> Result<Cid, ApiError> {
+    let version = options.cid_version;
+    // only the shard being filled is open
+    let mut current: Option<(CarWriter<BufWriter<File>>, PathBuf)> = None;
+    let mut count = 0;
+    let mut seen: HashSet<Cid> = HashSet::new();
+
+    let built = DagBuilder::new(options).build(files, &mut |cid, block| {
+        if seen.contains(cid) {
+            return Ok(());
+        }
+        let entry_size = (cid.to_bytes().len() + block.len() + 10) as u64;
+        let full = current.as_ref()
+            .map(|(writer, _)| writer.block_count() > 0 && writer.size() + entry_size > shard_size)
+            .unwrap_or(false);
+        if full {
+            if let Some((writer, path)) = current.take() {
+                on_shard(close_shard(writer, path, root)?)?;
+            }
+        }
+        if current.is_none() {
+            let path = dir.join(format!("shard-{}.car", count));
+            count += 1;
+            current = Some((CarWriter::new(BufWriter::new(File::create(&path)?), version)?, path));
+        }
+        if let Some((writer, _)) = current.as_mut() {
+            writer.put(cid, block)?;
+        }
+        seen.insert(cid.clone());
+        Ok(())
+    })?;
+
+    if let Some((writer, path)) = current.take() {
+        on_shard(close_shard(writer, path, root)?)?;
+    }
+    Ok(built.cid)
+}
+
+fn close_shard(writer: CarWriter<BufWriter<File>>, path: PathBuf, root: Option<&Cid>) -> Result<CarShard, ApiError> {
+    let size = writer.size();
+    match root {
+        Some(root) => writer.finish(root)?,
+        None => writer.close()?,
+    };
+    Ok(CarShard { path, size })
+}
+
+/// Largest car header `read_root` reads, a header holds little more than the roots
+const MAX_HEADER_SIZE: u64 = 1024 * 1024;
+
+/// Reads the first root cid from the header of the CARv1 archive at `path`
+pub fn read_root<P: AsRef<Path>>(path: P) -> Result<Cid, ApiError> {
+    let mut file = File::open(path)?;
+    let mut prefix = [0u8; 10];
+    let read = file.read(&mut prefix)?;
+    let (header_len, rest) = read_varint(&prefix[..read])?;
+    if header_len > MAX_HEADER_SIZE {
+        return Err(ApiError::GenericError(format!("Car header of {} bytes is larger than the {} bytes allowed", header_len, MAX_HEADER_SIZE)));
+    }
+
+    let mut header = vec![0u8; header_len as usize];
+    file.seek(SeekFrom::Start((read - rest.len()) as u64))?;
+    file.read_exact(&mut header)?;
+
+    // the first tag 42 in the header is the first entry of `roots`
+    let tag = header.windows(2).position(|window| window == [0xd8, 0x2a])
+        .ok_or_else(|| ApiError::GenericError("Car header has no root".to_string()))?;
+    let bytes = &header[tag + 2..];
+    let (len, start) = match bytes.first() {
+        Some(byte @ 0x40..=0x57) => ((byte - 0x40) as usize, 1),
+        Some(0x58) if bytes.len() > 1 => (bytes[1] as usize, 2),
+        _ => return Err(ApiError::GenericError("Car header root is not a cid".to_string())),
+    };
+    // skip the 0x00 multibase prefix in front of the binary cid
+    match bytes.get(start + 1..start + len) {
+        Some(cid) => Cid::from_bytes(cid),
+        None => Err(ApiError::GenericError("Car header is truncated".to_string())),
+    }
+}
+
+/// Offset of the root in a header written by `CarWriter` for a root of `root_len` bytes
+fn root_offset(root_len: usize) -> u64 {
+    let header_len = encode_header(&vec![0u8; root_len]).len();
+    let mut prefix = vec![];
+    write_varint(&mut prefix, header_len as u64);
+    (prefix.len() + header_len - root_len - HEADER_SUFFIX.len()) as u64
+}
+
+/// dag-cbor text string "version" followed by the integer 1
+const HEADER_SUFFIX: [u8; 9] = [0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x01];
+
+/// Encodes the dag-cbor header `{"roots": [root], "version": 1}` with keys in canonical order
+fn encode_header(root: &[u8]) -> Vec<u8> {
+    let mut header = vec![0xa2, 0x65];
+    header.extend_from_slice(b"roots");
+    // array of one element holding tag 42, a byte string of the cid behind a 0x00 multibase prefix
+    header.extend_from_slice(&[0x81, 0xd8, 0x2a, 0x58, (root.len() + 1) as u8, 0x00]);
+    header.extend_from_slice(root);
+    header.extend_from_slice(&HEADER_SUFFIX);
+    header
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use data_encoding::{BASE32_NOPAD, HEXLOWER, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use crate::errors::ApiError;
+
+/// Multicodec code for dag-pb (protobuf encoded merkle dag nodes)
+pub const DAG_PB: u64 = 0x70;
+/// Multicodec code for raw binary blocks
+pub const RAW: u64 = 0x55;
+/// Multihash code for sha2-256
+pub const SHA2_256: u64 = 0x12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CidVersion {
+    /// Legacy base58btc `Qm...` identifiers, always dag-pb and sha2-256
+    V0,
+    /// Self describing identifiers, printed as base32 `bafy...`/`bafk...`
+    V1,
+}
+
+/// Multibase encodings a cid can be printed in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Multibase {
+    /// Lowercase base32, prefixed with `b`, the default for v1 cids
+    #[default]
+    Base32,
+    /// Base58btc, prefixed with `z` for v1 cids and unprefixed for v0 cids
+    Base58Btc,
+    /// Lowercase hex, prefixed with `f`
+    Base16,
+}
+
+impl FromStr for Multibase {
+    type Err = ApiError;
+
+    fn from_str(base: &str) -> Result<Self, Self::Err> {
+        match base.trim().to_lowercase().as_str() {
+            "base32" => Ok(Multibase::Base32),
+            "base58btc" => Ok(Multibase::Base58Btc),
+            "base16" => Ok(Multibase::Base16),
+            other => Err(ApiError::GenericError(format!("Unknown multibase {}, valid bases are: base32, base58btc, base16", other))),
+        }
+    }
+}
+
+/// A content identifier computed from the bytes of a block.
+///
+/// Two cids are equal when their multihashes are, so the v0 and v1 forms of the same block
+/// compare equal while the same file chunked into different dags does not
+#[derive(Clone, Debug)]
+pub struct Cid {
+    version: CidVersion,
+    codec: u64,
+    multihash: Vec<u8>,
+}
+
+impl Cid {
+    /// Hashes `block` with sha2-256 and returns its cid
+    pub fn for_block(version: CidVersion, codec: u64, block: &[u8]) -> Cid {
+        let digest = Sha256::digest(block);
+        let mut multihash = vec![];
+        write_varint(&mut multihash, SHA2_256);
+        write_varint(&mut multihash, digest.len() as u64);
+        multihash.extend_from_slice(&digest);
+
+        Cid { version, codec, multihash }
+    }
+
+    /// Reads a cid from its binary form
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cid, ApiError> {
+        if bytes.len() == 34 && bytes[0] == SHA2_256 as u8 && bytes[1] == 32 {
+            return Ok(Cid { version: CidVersion::V0, codec: DAG_PB, multihash: bytes.to_vec() });
+        }
+        let (version, rest) = read_varint(bytes)?;
+        if version != 1 {
+            return Err(ApiError::GenericError(format!("Unsupported cid version {}", version)));
+        }
+        let (codec, multihash) = read_varint(rest)?;
+        check_multihash(multihash)?;
+        Ok(Cid { version: CidVersion::V1, codec, multihash: multihash.to_vec() })
+    }
+
+    /// The cid written in `base`. A v0 cid only exists in base58btc, so in any other base
+    /// it is written as the v1 cid of the same block
+    pub fn to_string_in(&self, base: Multibase) -> String {
+        let bytes = self.to_v1().to_bytes();
+        match (self.version, base) {
+            (CidVersion::V0, Multibase::Base58Btc) => bs58::encode(&self.multihash).into_string(),
+            (_, Multibase::Base32) => format!("b{}", BASE32_NOPAD.encode(&bytes).to_lowercase()),
+            (_, Multibase::Base58Btc) => format!("z{}", bs58::encode(&bytes).into_string()),
+            (_, Multibase::Base16) => format!("f{}", HEXLOWER.encode(&bytes)),
+        }
+    }
+
+    /// The same block as a v1 cid
+    pub fn to_v1(&self) -> Cid {
+        Cid { version: CidVersion::V1, ..self.clone() }
+    }
+
+    /// The same block as a v0 cid, only possible for dag-pb blocks hashed with sha2-256
+    pub fn to_v0(&self) -> Result<Cid, ApiError> {
+        if self.codec != DAG_PB || self.multihash.len() != 34 || self.multihash[0] != SHA2_256 as u8 {
+            return Err(ApiError::GenericError(format!("{} has no v0 form, v0 needs dag-pb and sha2-256", self)));
+        }
+        Ok(Cid { version: CidVersion::V0, ..self.clone() })
+    }
+
+    pub fn version(&self) -> CidVersion {
+        self.version
+    }
+
+    pub fn codec(&self) -> u64 {
+        self.codec
+    }
+
+    pub fn multihash(&self) -> &[u8] {
+        &self.multihash
+    }
+
+    /// Binary form of the cid as used inside dag-pb links and CAR files
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.version {
+            CidVersion::V0 => self.multihash.clone(),
+            CidVersion::V1 => {
+                let mut bytes = vec![];
+                write_varint(&mut bytes, 1);
+                write_varint(&mut bytes, self.codec);
+                bytes.extend_from_slice(&self.multihash);
+                bytes
+            }
+        }
+    }
+}
+
+impl PartialEq for Cid {
+    fn eq(&self, other: &Self) -> bool {
+        self.multihash == other.multihash
+    }
+}
+
+impl Eq for Cid {}
+
+impl Hash for Cid {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.multihash.hash(state);
+    }
+}
+
+/// Parses `Qm...` v0 cids and v1 cids in base32, base58btc or base16 multibase
+impl FromStr for Cid {
+    type Err = ApiError;
+
+    fn from_str(cid: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| ApiError::GenericError(format!("Invalid cid {}: {}", cid, reason));
+        if cid.len() == 46 && cid.starts_with("Qm") {
+            let bytes = bs58::decode(cid).into_vec().map_err(|e| invalid(&e.to_string()))?;
+            return Cid::from_bytes(&bytes);
+        }
+        let mut chars = cid.chars();
+        let bytes = match (chars.next(), chars.as_str()) {
+            (Some('b'), rest) | (Some('B'), rest) => BASE32_NOPAD.decode(rest.to_uppercase().as_bytes()).map_err(|e| invalid(&e.to_string()))?,
+            (Some('z'), rest) => bs58::decode(rest).into_vec().map_err(|e| invalid(&e.to_string()))?,
+            (Some('f'), rest) | (Some('F'), rest) => HEXLOWER_PERMISSIVE.decode(rest.as_bytes()).map_err(|e| invalid(&e.to_string()))?,
+            _ => return Err(invalid("unsupported multibase")),
+        };
+        let parsed = Cid::from_bytes(&bytes)?;
+        if parsed.version == CidVersion::V0 {
+            return Err(invalid("a v0 cid can only be written in base58btc"));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Writes v0 cids in base58btc and v1 cids in base32, the forms providers expect
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            CidVersion::V0 => write!(f, "{}", self.to_string_in(Multibase::Base58Btc)),
+            CidVersion::V1 => write!(f, "{}", self.to_string_in(Multibase::Base32)),
+        }
+    }
+}
+
+impl Serialize for Cid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cid = String::deserialize(deserializer)?;
+        cid.parse().map_err(|e: ApiError| serde::de::Error::custom(e.to_string()))
+    }
+}
+
+/// Checks `multihash` holds a hash code, a digest length and a digest of that length
+fn check_multihash(multihash: &[u8]) -> Result<(), ApiError> {
+    let (_, rest) = read_varint(multihash)?;
+    let (length, digest) = read_varint(rest)?;
+    if digest.len() as u64 != length {
+        return Err(ApiError::GenericError(format!("Multihash digest is {} bytes, expected {}", digest.len(), length)));
+    }
+    Ok(())
+}
+
+/// Reads an unsigned LEB128 varint, returning it with the bytes that follow it
+pub(crate) fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8]), ApiError> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    Err(ApiError::GenericError("Invalid varint".to_string()))
+}
+
+/// Appends `value` to `buf` as an unsigned LEB128 varint
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde_derive::Deserialize;
+use crate::errors::ApiError;
+use crate::registry::ProviderConfig;
+
+/// Name of the config file looked up in the working directory and the user config dir
+pub const CONFIG_FILE_NAME: &str = "patter.toml";
+
+/// Contents of a `patter.toml` file.
+///
+/// ```toml
+/// default_profile = "staging"
+///
+/// [profiles.staging]
+/// providers = ["pinata", "web3"]
+///
+/// [profiles.staging.options]
+/// shard_size = 52428800
+///
+/// [profiles.staging.pinata]
+/// api_key = "..."
+/// secret_api_key = { env = "STAGING_PINATA_SECRET" }
+/// api_url = "https://api.pinata.cloud"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PatterConfig {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// A named set of providers together with their credentials and settings
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// Defaults applied to every provider of the profile, each provider table overrides them
+    #[serde(default)]
+    pub options: HashMap<String, ConfigValue>,
+    /// One table per provider, keyed by provider name
+    #[serde(flatten)]
+    pub provider_configs: HashMap<String, HashMap<String, ConfigValue>>,
+}
+
+/// A value in a provider table, either written inline or referring to an env variable
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigValue {
+    Text(String),
+    Integer(i64),
+    Boolean(bool),
+    Env { env: String },
+}
+
+/// Providers and their configuration after a profile has been picked and its references resolved
+#[derive(Clone, Debug, Default)]
+pub struct ResolvedProfile {
+    pub name: String,
+    pub providers: Vec<String>,
+    pub configs: HashMap<String, ProviderConfig>,
+}
+
+impl PatterConfig {
+    /// Loads `patter.toml` from the working directory, or else from the user config dir.
+    /// Returns `None` when neither exists
+    pub fn load() -> Result<Option<PatterConfig>, ApiError> {
+        match PatterConfig::find() {
+            Some(path) => PatterConfig::from_file(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Path of the config file `load` reads, if there is one
+    pub fn find() -> Option<PathBuf> {
+        let project = PathBuf::from(CONFIG_FILE_NAME);
+        if project.is_file() {
+            return Some(project);
+        }
+        PatterConfig::user_path().filter(|path| path.is_file())
+    }
+
+    /// Path of the config file in the user config dir, whether it exists or not
+    pub fn user_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("patter").join(CONFIG_FILE_NAME))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<PatterConfig, ApiError> {
+        let contents = fs::read_to_string(&path)?;
+        PatterConfig::parse(&contents)
+            .map_err(|e| ApiError::GenericError(format!("Invalid config file {:?}: {}", path.as_ref(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<PatterConfig, ApiError> {
+        toml::from_str(contents).map_err(|e| ApiError::GenericError(e.to_string()))
+    }
+
+    /// Picks the profile called `name`, or the default profile when `name` is `None`.
+    /// Returns `None` when no name is given and the file has no default profile
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<ResolvedProfile>, ApiError> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let profile = match self.profiles.get(name) {
+            Some(profile) => profile,
+            None => {
+                let mut valid = self.profiles.keys().cloned().collect::<Vec<String>>();
+                valid.sort();
+                return Err(ApiError::UnknownProfile { name: name.to_string(), valid: valid.join(", ") });
+            }
+        };
+
+        let mut configs = HashMap::new();
+        for (provider, values) in profile.provider_configs.iter() {
+            let mut config = ProviderConfig::new();
+            for (key, value) in profile.options.iter().chain(values.iter()) {
+                config.insert(key.clone(), value.resolve(provider, key)?);
+            }
+            configs.insert(provider.clone(), config);
+        }
+        // providers without a table of their own still get the profile defaults
+        for provider in profile.providers.iter() {
+            if configs.contains_key(provider) {
+                continue;
+            }
+            let mut config = ProviderConfig::new();
+            for (key, value) in profile.options.iter() {
+                config.insert(key.clone(), value.resolve(provider, key)?);
+            }
+            configs.insert(provider.clone(), config);
+        }
+
+        Ok(Some(ResolvedProfile { name: name.to_string(), providers: profile.providers.clone(), configs }))
+    }
+}
+
+impl ConfigValue {
+    fn resolve(&self, provider: &str, field: &str) -> Result<String, ApiError> {
+        match self {
+            ConfigValue::Text(text) => Ok(text.clone()),
+            ConfigValue::Integer(number) => Ok(number.to_string()),
+            ConfigValue::Boolean(flag) => Ok(flag.to_string()),
+            ConfigValue::Env { env } => std::env::var(env).map_err(|_| ApiError::MissingConfig {
+                provider: provider.to_string(),
+                field: field.to_string(),
+                env: env.clone(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::process::{Command, Stdio};
+use reqwest::header::HeaderValue;
+use crate::config::PatterConfig;
+use crate::errors::ApiError;
+use crate::registry::ProviderConfig;
+
+/// Env variable naming a credential helper, used when the provider config names none
+pub const CREDENTIAL_HELPER_ENV: &str = "PATTER_CREDENTIAL_HELPER";
+/// Provider config key naming a credential helper
+pub const CREDENTIAL_HELPER_KEY: &str = "credential_helper";
+
+tokio::task_local! {
+    /// Set while `config_only` runs
+    static CONFIG_ONLY: bool;
+}
+
+/// Runs `f` with every `CredentialResolver` reading only the explicit and config values,
+/// skipping the env and the credential helper, so what `f` creates uses exactly those values
+pub fn config_only<R, F: FnOnce() -> R>(f: F) -> R {
+    CONFIG_ONLY.sync_scope(true, f)
+}
+
+/// A credential value, its `Debug` output never shows the value
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Looks up the credentials of one provider, trying in order the value passed explicitly,
+/// the env, the provider table of the config file and finally the credential helper.
+///
+/// A credential helper is a command called as `<helper> get <provider> <credential>`, which
+/// prints the credential on stdout, or nothing when it does not know it.
+#[derive(Clone, Debug)]
+pub struct CredentialResolver {
+    provider: String,
+    config: ProviderConfig,
+    helper: Option<String>,
+}
+
+impl CredentialResolver {
+    /// Creates a resolver reading the config file values from `config`
+    pub fn new<S: Into<String>>(provider: S, config: ProviderConfig) -> Self {
+        let helper = config.get(CREDENTIAL_HELPER_KEY).cloned()
+            .or_else(|| std::env::var(CREDENTIAL_HELPER_ENV).ok())
+            .filter(|helper| !helper.trim().is_empty());
+        CredentialResolver { provider: provider.into(), config, helper }
+    }
+
+    /// Creates a resolver reading the config file values from the default profile of `patter.toml`
+    pub fn load<S: Into<String>>(provider: S) -> Result<Self, ApiError> {
+        let provider = provider.into();
+        let config = match PatterConfig::load()? {
+            Some(config) => config.profile(None)?
+                .and_then(|mut profile| profile.configs.remove(&provider))
+                .unwrap_or_default(),
+            None => ProviderConfig::new(),
+        };
+        Ok(CredentialResolver::new(provider, config))
+    }
+
+    pub fn with_helper<S: Into<String>>(mut self, helper: S) -> Self {
+        self.helper = Some(helper.into());
+        self
+    }
+
+    /// Resolves the credential `name`, which the env holds as `env`
+    pub fn resolve(&self, explicit: Option<String>, name: &str, env: &str) -> Result<Secret, ApiError> {
+        self.find(explicit, name, env)?
+            .ok_or_else(|| ApiError::MissingCredential { provider: self.provider.clone(), name: name.to_string(), env: env.to_string() })
+    }
+
+    /// Like `resolve`, but returns `None` when no source knows the credential
+    pub fn find(&self, explicit: Option<String>, name: &str, env: &str) -> Result<Option<Secret>, ApiError> {
+        let config_only = CONFIG_ONLY.try_with(|only| *only).unwrap_or(false);
+        let from_env = || if config_only { None } else { std::env::var(env).ok() };
+        let configured = || self.config.get(name).cloned();
+        let value = explicit.or_else(from_env).or_else(configured);
+        let value = match value {
+            Some(value) => Some(value),
+            None if config_only => None,
+            None => self.run_helper(name)?,
+        };
+        Ok(value.filter(|value| !value.trim().is_empty()).map(|value| Secret(value.trim().to_string())))
+    }
+
+    /// Turns `value` into a header value hidden from `Debug` output
+    pub fn header(&self, name: &str, value: &str) -> Result<HeaderValue, ApiError> {
+        let mut header = HeaderValue::from_str(value).map_err(|_| ApiError::InvalidCredential {
+            provider: self.provider.clone(),
+            name: name.to_string(),
+            reason: "it contains characters not allowed in an http header".to_string(),
+        })?;
+        header.set_sensitive(true);
+        Ok(header)
+    }
+
+    fn run_helper(&self, name: &str) -> Result<Option<String>, ApiError> {
+        let helper = match &self.helper {
+            Some(helper) => helper,
+            None => return Ok(None),
+        };
+        let mut words = helper.split_whitespace();
+        let program = words.next().unwrap_or_default();
+        let failed = |message: String| ApiError::CredentialHelper {
+            helper: program.to_string(),
+            provider: self.provider.clone(),
+            name: name.to_string(),
+            message,
+        };
+
+        // stderr is dropped along with stdout, a failing helper may echo what it was given
+        let output = Command::new(program)
+            .args(words)
+            .args(["get", self.provider.as_str(), name])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(format!("exited with {}", output.status)));
+        }
+        let value = String::from_utf8(output.stdout).map_err(|_| failed("printed a credential that is not utf-8".to_string()))?;
+        Ok(Some(value).filter(|value| !value.trim().is_empty()))
+    }
+}
@@ -1,174 +1,287 @@
 use async_trait::async_trait;
-use std::{thread};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-use crate::api::data::{PinByFile, PinByHash, PinByHashResult, PinByJson, PinnedObject, UnPin};
+use crate::api::data::{PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinMetadata, PinOptions, PinRecord, PinnedObject, UnPin};
+use crate::car;
+use crate::cid::Cid;
 use crate::errors::ApiError;
+use crate::fanout::{FanOut, FanOutReport, SuccessPolicy};
+use crate::progress::{ProgressCallback, ProgressReporter};
+use crate::unixfs::ExpectedCids;
+use crate::utils::total_file_size;
 
 // todo: Implement first Ipfs provider to uploading files to ipfs and return cid and etc
 #[async_trait]
 pub trait StorageProvider {
     fn name(&self) -> String;
-    fn init(&self) -> bool;
+    /// Checks the credentials the provider was created with against its authentication test endpoint
+    async fn init(&self) -> Result<(), ApiError>;
     fn api_url(&self) -> String;
     async fn pin_file(&self,  pin_data: PinByFile) -> Result<PinnedObject, ApiError>;
     async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError>;
+    /// Uploads a pre-built CARv1 archive so the provider stores exactly that dag
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError>;
     async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError>;
-    async fn pin_directory(&self) -> Result<(), ApiError>;
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError>;
     async fn unpin(&self, options: UnPin) -> Result<(), ApiError>;
+    /// Every pin the provider holds that matches `filter`, going through all pages of results
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError>;
 }
 
 pub type SafeStorage = Box<dyn StorageProvider + Send + Sync>;
 
 pub struct PinFileData {
     pub(crate) files: Vec<String>,
-    pub(crate) providers: Vec<SafeStorage>
+    pub(crate) providers: Vec<SafeStorage>,
+    pub(crate) progress: Option<ProgressCallback>,
+    pub(crate) metadata: PinMetadata,
+    pub(crate) options: PinOptions,
 }
 
 pub struct PinJsonData {
+    pub(crate) file: String,
+    pub(crate) providers: Vec<SafeStorage>,
+    pub(crate) metadata: PinMetadata,
+    pub(crate) options: PinOptions,
+}
+
+pub struct PinCarData {
     pub(crate) file: String,
     pub(crate) providers: Vec<SafeStorage>
 }
 
 pub struct  PinHashData {
-    pub(crate) hash: String,
-    pub(crate) providers: Vec<SafeStorage>
+    pub(crate) hash: Cid,
+    pub(crate) providers: Vec<SafeStorage>,
+    /// Stored with the pin by `pin_by_hash`, `unpin` ignores it
+    pub(crate) metadata: PinMetadata,
+}
+
+pub struct ListPinsData {
+    pub(crate) providers: Vec<SafeStorage>,
+    pub(crate) filter: PinFilter,
 }
 
 impl PinFileData {
-    #[allow(unused)]
     pub fn new<S: Into<String>>(path: S, providers: Vec<SafeStorage>) -> Self {
-        PinFileData { files: vec![path.into()], providers }
+        PinFileData { files: vec![path.into()], providers, progress: None, metadata: PinMetadata::default(), options: PinOptions::default() }
+    }
+
+    /// Reports the bytes sent to each provider to `progress` while the files upload
+    pub fn with_progress(mut self, progress: ProgressCallback) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Name and tags every provider stores with the pin
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// How every provider stores the upload, as far as it supports the options
+    pub fn with_options(mut self, options: PinOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl PinJsonData {
+    pub fn new<S: Into<String>>(path: S, providers: Vec<SafeStorage>) -> Self {
+        PinJsonData { file: path.into(), providers, metadata: PinMetadata::default(), options: PinOptions::default() }
+    }
+
+    /// Name and tags every provider stores with the pin
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// How every provider stores the upload, as far as it supports the options
+    pub fn with_options(mut self, options: PinOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl PinCarData {
+    pub fn new<S: Into<String>>(path: S, providers: Vec<SafeStorage>) -> Self {
+        PinCarData { file: path.into(), providers }
+    }
+}
+
+impl PinHashData {
+    pub fn new(hash: Cid, providers: Vec<SafeStorage>) -> Self {
+        PinHashData { hash, providers, metadata: PinMetadata::default() }
+    }
+
+    /// Name and tags every provider stores with the pin
+    pub fn with_metadata(mut self, metadata: PinMetadata) -> Self {
+        self.metadata = metadata;
+        self
     }
 }
 
-pub struct PatterApi {}
+impl ListPinsData {
+    pub fn new(providers: Vec<SafeStorage>) -> Self {
+        ListPinsData { providers, filter: PinFilter::default() }
+    }
+
+    /// Only lists the pins matching `filter`, every pin by default
+    pub fn with_filter(mut self, filter: PinFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+}
+
+/// Pins to many providers at once, see `FanOut` for how the providers are called
+#[derive(Clone, Debug, Default)]
+pub struct PatterApi {
+    pub fan_out: FanOut,
+    /// Operations not meeting it return `ApiError::PolicyNotMet`, holding the report of the operation
+    pub policy: SuccessPolicy,
+}
 
 impl PatterApi {
     pub fn new() -> Self {
-        PatterApi {}
-    }
-
-    pub async fn pin_file(&self, pin_data: PinFileData) -> Result<Vec<PinnedObject>, ApiError> {
-        let mut handles = vec![];
-
-        let results: Arc<Mutex<Vec<PinnedObject>>> = Arc::new(Mutex::new(vec![]));
-        let files = Arc::new(pin_data.files);
-        for provider in pin_data.providers {
-            let results:  Arc<Mutex<Vec<PinnedObject>>>  = Arc::clone(&results);
-            let provider = Arc::new(provider);
-            let files = Arc::clone(&files);
-            println!("Creating async thread for provider {}", provider.name());
-
-            let handle = thread::spawn(move || async move {
-                let result = provider.pin_file(PinByFile { files: files.to_vec() }).await;
-                if let Ok(pinned_object) =  result {
-                    println!("Pinned Result {:?} to provider {}", pinned_object, provider.name());
-                    let mut r = results.lock().unwrap();
-                    r.push(pinned_object);
-                } else {
-                    println!("Error Pinning file to provider {}", provider.name());
-                    println!("Error {:?}", result);
-                }
-            });
-            handles.push(handle);
-        }
-        for handle in handles {
-            handle.join().unwrap().await;
-        };
+        PatterApi::default()
+    }
+
+    /// Calls at most `concurrency` providers at the same time
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.fan_out = self.fan_out.with_concurrency(concurrency);
+        self
+    }
+
+    /// Gives up on a provider that has not answered within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.fan_out = self.fan_out.with_timeout(timeout);
+        self
+    }
+
+    /// Stops the calls still running when `cancel` is cancelled
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.fan_out = self.fan_out.with_cancellation(cancel);
+        self
+    }
 
-        let getter = results.lock().unwrap().to_vec();
-        Ok(getter)
-    }
-
-    pub async fn pin_json(&self, pin_data: PinJsonData) -> Result<Vec<PinnedObject>, ApiError> {
-        let mut handles = vec![];
-
-        let results: Arc<Mutex<Vec<PinnedObject>>> = Arc::new(Mutex::new(vec![]));
-        let file = Arc::new(pin_data.file);
-        for provider in pin_data.providers {
-            let results:  Arc<Mutex<Vec<PinnedObject>>>  = Arc::clone(&results);
-            let provider = Arc::new(provider);
-            let file = Arc::clone(&file);
-            println!("Creating async thread for provider {}", provider.name());
-            let handle = thread::spawn(move || async move {
-                let result = provider.pin_json(PinByJson { file: file.to_string() }).await;
-                match result {
-                    Ok(pinned_json) => {
-                        println!("Pinned Result {:?} to provider {}", pinned_json, provider.name());
-                        let mut r = results.lock().unwrap();
-                        r.push(pinned_json);
+    /// Sets what has to succeed for an operation to succeed, every provider by default
+    pub fn with_policy(mut self, policy: SuccessPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub async fn pin_file(&self, pin_data: PinFileData) -> Result<FanOutReport<PinnedObject>, ApiError> {
+        let expected = Arc::new(ExpectedCids::compute_wrapped(&pin_data.files, pin_data.options.wrap_with_directory)?);
+        println!("Expected cid {} (v0) / {} (v1)", expected.v0, expected.v1);
+
+        let total_size = match pin_data.progress {
+            Some(_) => total_file_size(&pin_data.files)?,
+            None => 0,
+        };
+        let files = pin_data.files;
+        let callback = pin_data.progress;
+        let metadata = pin_data.metadata;
+        let options = pin_data.options;
+        let report = self.fan_out.run(pin_data.providers, |provider| {
+            let mut pin = PinByFile::new(files.clone()).with_metadata(metadata.clone()).with_options(options.clone());
+            let progress = callback.clone().map(|callback| ProgressReporter::new(provider.name(), total_size, callback));
+            if let Some(progress) = progress.clone() {
+                pin = pin.with_progress(progress);
+            }
+            let expected = Arc::clone(&expected);
+            // only polled once the provider got its turn, so the upload starts here
+            async move {
+                if let Some(progress) = progress.as_ref() {
+                    progress.started();
+                }
+                let result = provider.pin_file(pin).await;
+                if let Some(progress) = progress {
+                    progress.finished(result.is_ok());
+                }
+                result.map(|mut pinned_object| {
+                    let verified = expected.matches(&pinned_object.ipfs_hash);
+                    if !verified {
+                        println!("Cid mismatch: provider {} stored {}, expected {} (v0) or {} (v1)", provider.name(), pinned_object.ipfs_hash, expected.v0, expected.v1);
                     }
-                    Err(..) => {
-                        println!("Error Pinning file to provider {}", provider.name());
-                        println!("Error {:?}", result);
+                    pinned_object.verified = Some(verified);
+                    pinned_object
+                })
+            }
+        }).await;
+
+        self.policy.enforce(report)
+    }
+
+    pub async fn pin_json(&self, pin_data: PinJsonData) -> Result<FanOutReport<PinnedObject>, ApiError> {
+        let file = pin_data.file;
+        let metadata = pin_data.metadata;
+        let options = pin_data.options;
+        let report = self.fan_out.run(pin_data.providers, |provider| {
+            let pin = PinByJson::new(file.clone()).with_metadata(metadata.clone()).with_options(options.clone());
+            async move { provider.pin_json(pin).await }
+        }).await;
+
+        self.policy.enforce(report)
+    }
+
+    pub async fn pin_car(&self, pin_data: PinCarData) -> Result<FanOutReport<PinnedObject>, ApiError> {
+        let root = Arc::new(car::read_root(&pin_data.file)?);
+        println!("Car root cid {}", root);
+
+        let file = pin_data.file;
+        let report = self.fan_out.run(pin_data.providers, |provider| {
+            let pin = PinByCar { file: file.clone() };
+            let root = Arc::clone(&root);
+            async move {
+                provider.pin_car(pin).await.map(|mut pinned_car| {
+                    // compared by multihash, so a root confirmed as v0 or in another base still matches
+                    let verified = pinned_car.ipfs_hash.parse::<Cid>().map(|cid| cid == *root).unwrap_or(false);
+                    if !verified {
+                        println!("Cid mismatch: provider {} confirmed root {}, expected {}", provider.name(), pinned_car.ipfs_hash, root);
                     }
-                }
-            });
-            handles.push(handle);
-        }
-        for handle in handles {
-            handle.join().unwrap().await;
-        };
+                    pinned_car.verified = Some(verified);
+                    pinned_car
+                })
+            }
+        }).await;
 
-        let getter = results.lock().unwrap().to_vec();
-        Ok(getter)
-    }
-
-    pub async fn pin_by_hash(&self, pin_data: PinHashData) -> Result<Vec<PinByHashResult>, ApiError> {
-        let mut handles = vec![];
-
-        let results: Arc<Mutex<Vec<PinByHashResult>>> = Arc::new(Mutex::new(vec![]));
-        let hash = Arc::new(pin_data.hash);
-        for provider in pin_data.providers {
-            let results:  Arc<Mutex<Vec<PinByHashResult>>>  = Arc::clone(&results);
-            let provider = Arc::new(provider);
-            let hash = Arc::clone(&hash);
-            println!("Pin hash: {}", &hash);
-            let handle = thread::spawn(move || async move {
-                let result = provider.pin_by_hash(PinByHash { hash_to_pin: hash.to_string() }).await;
-                if let Ok(pinned_hash) =  result {
-                    println!("Pinned Result {:?} to provider {}", pinned_hash, provider.name());
-                    let mut r = results.lock().unwrap();
-                    r.push(pinned_hash);
-                } else {
-                    println!("Error Pinning hash to {}", provider.name());
-                    println!("Error {:?}", result);
-                }
-            });
-            handles.push(handle);
-        }
-        for handle in handles {
-            handle.join().unwrap().await;
-        };
+        self.policy.enforce(report)
+    }
 
-        let getter = results.lock().unwrap().to_vec();
-        Ok(getter)
-    }
-
-    pub async fn unpin(&self, pin_data: PinHashData) -> Result<(), ApiError> {
-        let mut handles = vec![];
-
-        let hash = Arc::new(pin_data.hash);
-        for provider in pin_data.providers {
-            let provider = Arc::new(provider);
-            let hash = Arc::clone(&hash);
-            println!("Unpin Cid: {}", &hash);
-            let handle = thread::spawn(move || async move {
-                let result = provider.unpin(UnPin { cid: hash.to_string() }).await;
-                if let Ok(_) =  result {
-                    println!("UnPinned Result to provider {}", provider.name());
-                } else {
-                    println!("Error Removing hash from {}", provider.name());
-                    println!("Error {:?}", result);
-                }
-            });
-            handles.push(handle);
-        }
-        for handle in handles {
-            handle.join().unwrap().await;
-        };
+    pub async fn pin_by_hash(&self, pin_data: PinHashData) -> Result<FanOutReport<PinByHashResult>, ApiError> {
+        println!("Pin hash: {}", &pin_data.hash);
+        let hash = pin_data.hash;
+        let metadata = pin_data.metadata;
+        let report = self.fan_out.run(pin_data.providers, |provider| {
+            let pin = PinByHash::new(hash.clone()).with_metadata(metadata.clone());
+            async move { provider.pin_by_hash(pin).await }
+        }).await;
+
+        self.policy.enforce(report)
+    }
+
+    pub async fn unpin(&self, pin_data: PinHashData) -> Result<FanOutReport<()>, ApiError> {
+        println!("Unpin Cid: {}", &pin_data.hash);
+        let hash = pin_data.hash;
+        let report = self.fan_out.run(pin_data.providers, |provider| {
+            let unpin = UnPin { cid: hash.clone() };
+            async move { provider.unpin(unpin).await }
+        }).await;
+
+        self.policy.enforce(report)
+    }
+
+    /// Lists the pins of every provider, `FanOutReport::merged` shows which providers hold each cid
+    pub async fn list_pins(&self, list_data: ListPinsData) -> Result<FanOutReport<Vec<PinRecord>>, ApiError> {
+        let filter = list_data.filter;
+        let report = self.fan_out.run(list_data.providers, |provider| {
+            let filter = filter.clone();
+            async move { provider.list_pins(filter).await }
+        }).await;
 
-        Ok(())
+        self.policy.enforce(report)
     }
 }
@@ -1,5 +1,9 @@
+// the `Fail` derive predates the non_local_definitions lint
+#![allow(non_local_definitions)]
+use std::any::Any;
 use failure::Fail;
 pub use failure::Error;
+use crate::fanout::FanOutReport;
 
 #[derive(Debug, Fail)]
 pub enum ApiError {
@@ -11,6 +15,51 @@ pub enum ApiError {
 
     #[fail(display = "Error: {}", _0)]
     GenericError(String),
+
+    #[fail(display = "HTTP {}: {}", status, message)]
+    HttpStatus { status: u16, message: String },
+
+    #[fail(display = "Unknown provider {}, valid providers are: {}", name, valid)]
+    UnknownProvider { name: String, valid: String },
+
+    #[fail(display = "Unknown profile {}, valid profiles are: {}", name, valid)]
+    UnknownProfile { name: String, valid: String },
+
+    #[fail(display = "No {} found for {}: pass it explicitly, set {}, add it to patter.toml or configure a credential helper", name, provider, env)]
+    MissingCredential { provider: String, name: String, env: String },
+
+    #[fail(display = "Invalid {} for {}: {}", name, provider, reason)]
+    InvalidCredential { provider: String, name: String, reason: String },
+
+    #[fail(display = "The {} key is missing the {} scope: {}", provider, scope, message)]
+    MissingScope { provider: String, scope: String, message: String },
+
+    #[fail(display = "Credential helper {} could not provide {} for {}: {}", helper, name, provider, message)]
+    CredentialHelper { helper: String, provider: String, name: String, message: String },
+
+    #[fail(display = "{} did not answer within {}", provider, timeout)]
+    Timeout { provider: String, timeout: String },
+
+    #[fail(display = "{} was cancelled", provider)]
+    Cancelled { provider: String },
+
+    /// `report` holds the `FanOutReport` of the operation, see `ApiError::report`
+    #[fail(display = "{} of {} providers succeeded but {} had to: {}", succeeded, total, policy, failures)]
+    PolicyNotMet { policy: String, succeeded: usize, total: usize, failures: String, report: Option<Box<dyn Any + Send + Sync>> },
+
+    #[fail(display = "Provider {} needs {} to be configured (env {})", provider, field, env)]
+    MissingConfig { provider: String, field: String, env: String },
+}
+
+impl ApiError {
+    /// The outcome of every provider of an operation that did not meet its success policy,
+    /// so what did succeed is not lost. `T` is what the operation returns for each provider
+    pub fn report<T: 'static>(&self) -> Option<&FanOutReport<T>> {
+        match self {
+            ApiError::PolicyNotMet { report: Some(report), .. } => report.downcast_ref::<FanOutReport<T>>(),
+            _ => None,
+        }
+    }
 }
 
 impl From<reqwest::Error> for ApiError {
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use crate::api::data::{JobStatus, PinRecord, PinnedObject};
+use crate::cid::Cid;
+use crate::data::SafeStorage;
+use crate::errors::ApiError;
+use crate::retry::count_retries;
+
+/// Runs one operation against many providers at once on the tokio runtime
+#[derive(Clone, Debug, Default)]
+pub struct FanOut {
+    /// Most providers called at the same time, 0 calls them all at once
+    pub concurrency: usize,
+    /// How long each provider gets to answer, counted from when its call starts
+    pub timeout: Option<Duration>,
+    /// Stops every call still running or waiting for its turn when cancelled
+    pub cancel: CancellationToken,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        FanOut::default()
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Calls `operation` for every provider, each on its own task, and reports the outcome of
+    /// every provider in the order the providers were given
+    pub async fn run<T, F, Fut>(&self, providers: Vec<SafeStorage>, operation: F) -> FanOutReport<T>
+        where T: Send + 'static,
+              F: Fn(Arc<SafeStorage>) -> Fut,
+              Fut: Future<Output = Result<T, ApiError>> + Send + 'static
+    {
+        let names = providers.iter().map(|provider| provider.name()).collect::<Vec<String>>();
+        let limit = if self.concurrency == 0 { providers.len().max(1) } else { self.concurrency };
+        let semaphore = Arc::new(Semaphore::new(limit));
+        let mut tasks = JoinSet::new();
+
+        for (index, provider) in providers.into_iter().enumerate() {
+            let call = operation(Arc::new(provider));
+            let name = names[index].clone();
+            let semaphore = Arc::clone(&semaphore);
+            let cancel = self.cancel.clone();
+            let timeout = self.timeout;
+            tasks.spawn(async move {
+                let retries = Arc::new(AtomicU32::new(0));
+                let mut started = None;
+                let result = tokio::select! {
+                    _ = cancel.cancelled() => Err(ApiError::Cancelled { provider: name.clone() }),
+                    result = call_with_limits(&semaphore, count_retries(Arc::clone(&retries), call), timeout, &name, &mut started) => result,
+                };
+                let outcome = ProviderOutcome {
+                    provider: name,
+                    result,
+                    duration: started.map(|started: Instant| started.elapsed()).unwrap_or_default(),
+                    attempts: retries.load(Ordering::Relaxed) + 1,
+                };
+                (index, outcome)
+            });
+        }
+
+        let mut outcomes = names.iter().map(|_| None).collect::<Vec<Option<ProviderOutcome<T>>>>();
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok((index, outcome)) => outcomes[index] = Some(outcome),
+                Err(e) => println!("Provider task stopped: {}", e),
+            }
+        }
+        let outcomes = names.into_iter().zip(outcomes)
+            .map(|(name, outcome)| outcome.unwrap_or_else(|| ProviderOutcome {
+                result: Err(ApiError::GenericError(format!("{} stopped before answering", name))),
+                provider: name,
+                duration: Duration::ZERO,
+                attempts: 1,
+            }))
+            .collect();
+        FanOutReport { outcomes }
+    }
+}
+
+/// What one provider answered during a fan-out
+#[derive(Debug)]
+pub struct ProviderOutcome<T> {
+    pub provider: String,
+    pub result: Result<T, ApiError>,
+    /// Time from the call starting to the provider answering, waiting for a free slot excluded
+    pub duration: Duration,
+    /// 1 plus the retries of all the requests the call made, so 1 when nothing was retried.
+    /// A call sending several requests, such as a paged listing or a multipart upload, counts
+    /// the retries of each of them, so this is not the number of times the call itself ran
+    pub attempts: u32,
+}
+
+impl<T> ProviderOutcome<T> {
+    pub fn succeeded(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// The outcome of every provider of a fan-out, in the order the providers were given
+#[derive(Debug)]
+pub struct FanOutReport<T> {
+    pub outcomes: Vec<ProviderOutcome<T>>,
+}
+
+impl<T> FanOutReport<T> {
+    /// True when every provider succeeded, also for a fan-out without providers
+    pub fn all_succeeded(&self) -> bool {
+        self.outcomes.iter().all(|outcome| outcome.succeeded())
+    }
+
+    /// Providers that failed, with their errors
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &ApiError)> {
+        self.outcomes.iter().filter_map(|outcome| outcome.result.as_ref().err().map(|e| (outcome.provider.as_str(), e)))
+    }
+
+    /// Values of the providers that succeeded
+    pub fn successes(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.outcomes.iter().filter_map(|outcome| outcome.result.as_ref().ok().map(|value| (outcome.provider.as_str(), value)))
+    }
+
+    /// The outcome of the provider called `name`
+    pub fn outcome(&self, name: &str) -> Option<&ProviderOutcome<T>> {
+        self.outcomes.iter().find(|outcome| outcome.provider == name)
+    }
+
+    /// Values of the providers that succeeded, dropping the failures
+    pub fn into_successes(self) -> Vec<T> {
+        self.outcomes.into_iter().filter_map(|outcome| outcome.result.ok()).collect()
+    }
+}
+
+/// Waits for a free slot, then runs `call` within `timeout`, setting `started` when the call starts
+async fn call_with_limits<T, Fut>(semaphore: &Semaphore, call: Fut, timeout: Option<Duration>, name: &str, started: &mut Option<Instant>) -> Result<T, ApiError>
+    where Fut: Future<Output = Result<T, ApiError>>
+{
+    let _permit = semaphore.acquire().await
+        .map_err(|_| ApiError::GenericError("Fan out closed".to_string()))?;
+    *started = Some(Instant::now());
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call).await
+            .unwrap_or_else(|_| Err(ApiError::Timeout { provider: name.to_string(), timeout: format!("{:?}", timeout) })),
+        None => call.await,
+    }
+}
+
+impl FanOutReport<PinnedObject> {
+    /// Groups the providers that succeeded by the content they stored
+    pub fn agreement(&self) -> CidAgreement {
+        let mut agreement = CidAgreement::default();
+        for (name, pinned) in self.successes() {
+            match pinned.ipfs_hash.parse::<Cid>() {
+                Ok(cid) => match agreement.groups.iter_mut().find(|(stored, _)| *stored == cid) {
+                    Some((_, providers)) => providers.push(name.to_string()),
+                    None => agreement.groups.push((cid.to_v1(), vec![name.to_string()])),
+                },
+                Err(_) => agreement.invalid.push((name.to_string(), pinned.ipfs_hash.clone())),
+            }
+        }
+        agreement
+    }
+}
+
+/// Which providers stored the same content, compared by multihash so a v0 and a v1 cid of
+/// the same dag agree. Providers that chunked the content differently land in different groups
+#[derive(Clone, Debug, Default)]
+pub struct CidAgreement {
+    /// Every root stored, as a v1 cid, with the providers that stored it
+    pub groups: Vec<(Cid, Vec<String>)>,
+    /// Providers that answered with something that is not a cid, with their answer
+    pub invalid: Vec<(String, String)>,
+}
+
+impl CidAgreement {
+    /// True when every provider that succeeded stored the same dag
+    pub fn agreed(&self) -> bool {
+        self.groups.len() <= 1 && self.invalid.is_empty()
+    }
+}
+
+impl fmt::Display for CidAgreement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.agreed() {
+            return match self.groups.first() {
+                Some((cid, _)) => write!(f, "all providers stored {}", cid),
+                None => write!(f, "no provider stored anything"),
+            };
+        }
+        let mut stored = self.groups.iter()
+            .map(|(cid, providers)| format!("{} stored {}", providers.join(", "), cid))
+            .collect::<Vec<String>>();
+        stored.extend(self.invalid.iter().map(|(provider, answer)| format!("{} answered {:?}", provider, answer)));
+        write!(f, "providers stored different dags: {}", stored.join("; "))
+    }
+}
+
+impl FanOutReport<Vec<PinRecord>> {
+    /// One entry per pinned content with the providers holding it, in the order the providers
+    /// first listed them. Cids are compared by multihash, so a v0 and a v1 cid of the same dag
+    /// are one entry
+    pub fn merged(&self) -> Vec<MergedPin> {
+        let mut merged: Vec<MergedPin> = vec![];
+        let mut by_cid: HashMap<Cid, usize> = HashMap::new();
+        // answers that are not cids can only be matched as they are written
+        let mut by_text: HashMap<String, usize> = HashMap::new();
+        for (name, records) in self.successes() {
+            for record in records.iter() {
+                let cid = record.cid.parse::<Cid>().ok();
+                let index = match cid.as_ref() {
+                    Some(cid) => by_cid.get(cid).copied(),
+                    None => by_text.get(&record.cid).copied(),
+                };
+                if let Some(index) = index {
+                    merged[index].add(name, record);
+                    continue;
+                }
+                match cid {
+                    Some(cid) => by_cid.insert(cid, merged.len()),
+                    None => by_text.insert(record.cid.clone(), merged.len()),
+                };
+                merged.push(MergedPin { record: record.clone(), providers: vec![name.to_string()] });
+            }
+        }
+        merged
+    }
+}
+
+/// A content pinned on one or more providers
+#[derive(Clone, Debug)]
+pub struct MergedPin {
+    /// As the first provider listed it, with the name, size, date and tags it left out
+    /// taken from the other providers
+    pub record: PinRecord,
+    pub providers: Vec<String>,
+}
+
+impl MergedPin {
+    fn add(&mut self, provider: &str, record: &PinRecord) {
+        if self.record.metadata.name.is_none() {
+            self.record.metadata.name = record.metadata.name.clone();
+        }
+        self.record.size = self.record.size.or(record.size);
+        if self.record.date.is_none() {
+            self.record.date = record.date.clone();
+        }
+        for (key, value) in record.metadata.tags.iter() {
+            self.record.metadata.tags.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        if !self.providers.iter().any(|name| name == provider) {
+            self.providers.push(provider.to_string());
+        }
+    }
+}
+
+impl fmt::Display for MergedPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.record.cid)?;
+        if self.record.status != JobStatus::Pinned {
+            write!(f, " ({:?})", self.record.status)?;
+        }
+        if let Some(name) = self.record.name() {
+            write!(f, " {:?}", name)?;
+        }
+        if let Some(size) = self.record.size {
+            write!(f, " {} bytes", size)?;
+        }
+        if let Some(date) = self.record.date.as_deref() {
+            write!(f, " pinned {}", date)?;
+        }
+        for (key, value) in self.record.metadata.tags.iter() {
+            write!(f, " {}={}", key, value)?;
+        }
+        write!(f, " on {}", self.providers.join(", "))
+    }
+}
+
+/// What has to succeed for a fan-out to count as a success
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SuccessPolicy {
+    /// Every provider
+    #[default]
+    All,
+    /// At least this many providers
+    AtLeast(usize),
+    /// At least one provider
+    Any,
+    /// Every provider named, by `StorageProvider::name`, whatever the other providers answer
+    Required(Vec<String>),
+}
+
+impl SuccessPolicy {
+    /// Checks `report` against the policy, the error lists every provider that failed
+    pub fn check<T>(&self, report: &FanOutReport<T>) -> Result<(), ApiError> {
+        let succeeded = report.successes().count();
+        let met = match self {
+            SuccessPolicy::All => succeeded == report.outcomes.len(),
+            SuccessPolicy::AtLeast(count) => succeeded >= *count,
+            SuccessPolicy::Any => succeeded > 0,
+            SuccessPolicy::Required(names) => names.iter()
+                .all(|name| report.outcome(name).map(|outcome| outcome.succeeded()).unwrap_or(false)),
+        };
+        if met {
+            return Ok(());
+        }
+
+        let mut failures = report.failures().map(|(name, e)| format!("{}: {}", name, e)).collect::<Vec<String>>();
+        if let SuccessPolicy::Required(names) = self {
+            failures.extend(names.iter().filter(|name| report.outcome(name).is_none()).map(|name| format!("{}: not among the providers", name)));
+        }
+        Err(ApiError::PolicyNotMet { policy: self.to_string(), succeeded, total: report.outcomes.len(), failures: failures.join("; "), report: None })
+    }
+
+    /// Like `check`, handing `report` back when the policy is met and keeping it in the error
+    /// otherwise, where `ApiError::report` finds it
+    pub fn enforce<T: Send + Sync + 'static>(&self, report: FanOutReport<T>) -> Result<FanOutReport<T>, ApiError> {
+        match self.check(&report) {
+            Ok(()) => Ok(report),
+            Err(ApiError::PolicyNotMet { policy, succeeded, total, failures, .. }) => {
+                Err(ApiError::PolicyNotMet { policy, succeeded, total, failures, report: Some(Box::new(report)) })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl fmt::Display for SuccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuccessPolicy::All => write!(f, "all providers"),
+            SuccessPolicy::AtLeast(count) => write!(f, "at least {} providers", count),
+            SuccessPolicy::Any => write!(f, "any provider"),
+            SuccessPolicy::Required(names) => write!(f, "{}", names.join(", ")),
+        }
+    }
+}
+
+/// Parses `all`, `any`, a number of providers, or comma separated provider names
+impl FromStr for SuccessPolicy {
+    type Err = ApiError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        let policy = policy.trim();
+        match policy {
+            "all" => Ok(SuccessPolicy::All),
+            "any" => Ok(SuccessPolicy::Any),
+            _ => match policy.parse::<usize>() {
+                Ok(count) => Ok(SuccessPolicy::AtLeast(count)),
+                Err(_) => {
+                    let names = policy.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()).map(|name| name.to_string()).collect::<Vec<String>>();
+                    if names.is_empty() {
+                        return Err(ApiError::GenericError("The success policy needs all, any, a number or provider names".to_string()));
+                    }
+                    Ok(SuccessPolicy::Required(names))
+                }
+            },
+        }
+    }
+}
@@ -1,20 +1,40 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
-use clap::{arg, Parser};
-pub use data::{StorageProvider,  PatterApi, SafeStorage};
-use api::data::PinnedObject;
-use errors::*;
-pub use providers::{web3_storage::Web3StorageProvider, pinata::PinataProvider};
-use crate::api::data::PinByHashResult;
-use crate::data::{PinFileData, PinHashData, PinJsonData};
+use std::path::Path;
+use clap::Parser;
+pub use data::{StorageProvider,  PatterApi, SafeStorage, PinFileData, PinJsonData, PinCarData, PinHashData, ListPinsData};
+pub use api::data::{PinnedObject, PinByHashResult, PinMetadata, PinOptions, PinFilter, PinRecord, JobStatus};
+pub use errors::ApiError;
+pub use providers::{web3_storage::Web3StorageProvider, pinata::PinataProvider, pinning_service::PinningServiceProvider, kubo::KuboProvider, s3_compatible::S3CompatibleProvider, memory::MemoryProvider, chaos::{ChaosProvider, ChaosConfig}};
+pub use registry::{ProviderRegistry, ProviderConfig, ConfigField};
+pub use config::PatterConfig;
+pub use credentials::{CredentialResolver, Secret};
+pub use progress::{Progress, ProgressCallback, ProgressReporter};
+pub use retry::RetryPolicy;
+pub use fanout::{CidAgreement, FanOut, FanOutReport, MergedPin, ProviderOutcome, SuccessPolicy};
+pub use cid::{Cid, CidVersion, Multibase};
+use crate::unixfs::{DagOptions, ExpectedCids};
 
 mod utils;
 mod api;
 mod data;
 mod errors;
+pub mod car;
+pub mod cid;
+pub mod config;
+pub mod credentials;
+pub mod login;
+pub mod fanout;
+pub mod progress;
+pub mod retry;
 pub mod providers;
+pub mod registry;
+pub mod unixfs;
 
-#[allow(unused_variables)]
+/// Providers used when `--provider` is not given
+pub const DEFAULT_PROVIDERS: &str = "pinata,web3";
 
+#[allow(unused_variables)]
 /// Cli app to upload files to ipfs storage provider
 #[derive(Parser, Debug)]
 #[clap(author="Patter", about="A rust library for pinning data to ipfs")]
@@ -23,6 +43,7 @@ pub struct Args {
     #[arg(short, long)]
     pub action: String,
 
+    /// Comma separated providers to use, e.g. `pinata,web3`. Defaults to pinata and web3
     #[arg(short, long)]
     pub provider: Option<String>,
 
@@ -30,9 +51,63 @@ pub struct Args {
     #[arg(short, long)]
     pub file_path: Option<String>,/// Path to file to be uploaded
 
-    /// hash to be pinned
+    /// Cid to pin or unpin, a `Qm...` v0 cid or a v1 cid in base32, base58btc or base16
+    #[arg(long, value_parser = parse_cid)]
+    pub hash: Option<Cid>,
+
+    /// Name stored with the pin, or for `list` part of the name of the pins to list
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Tag stored with the pin, or for `list` a tag the pins must have, written as `key=value`,
+    /// can be given more than once
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+
+    /// `list` only lists pins created on or after this date, `YYYY-MM-DD` or an ISO 8601 timestamp
+    #[arg(long, value_parser = parse_date)]
+    pub after: Option<String>,
+
+    /// `list` only lists pins created before this date, `YYYY-MM-DD` or an ISO 8601 timestamp
+    #[arg(long, value_parser = parse_date)]
+    pub before: Option<String>,
+
+    /// `list` only lists pins with this status, e.g. `pinned`, `pinning`, `queued` or `failed`
+    #[arg(long, value_parser = parse_status)]
+    pub status: Option<JobStatus>,
+
+    /// Cid version the providers should answer with, 0 or 1
+    #[arg(long)]
+    pub cid_version: Option<u8>,
+
+    /// Wraps the upload in a directory so the file keeps its name
+    #[arg(long)]
+    pub wrap: bool,
+
+    /// Copies to keep in a region, written as `REGION=COUNT`, e.g. `FRA1=1`, can be given more than once
+    #[arg(long = "replicate")]
+    pub replication: Vec<String>,
+
+    /// Option sent to one provider only, written as `provider.key=value`, e.g. `pinata.groupId=backups`
+    #[arg(long = "provider-option")]
+    pub provider_options: Vec<String>,
+
+    /// Base cids are printed in: base32 (default), base58btc or base16
+    #[arg(long, value_parser = parse_multibase)]
+    pub cid_base: Option<Multibase>,
+
+    /// Path the car archive is written to, defaults to `<file name>.car`
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Profile of patter.toml to take providers and credentials from
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// What has to succeed: `all` (default, `any` for `list`), `any`, a number of providers, or
+    /// comma separated provider names that must all succeed, e.g. `pinata,web3`
     #[arg(long)]
-    pub hash: Option<String>,
+    pub quorum: Option<String>,
 }
 
 /// Takes an arg of type Args and runs the app using the
@@ -45,72 +120,256 @@ pub struct Args {
 /// let cwd = env::current_dir().unwrap();
 /// let path = String::from(cwd.to_string_lossy());
 /// # tokio_test::block_on(async {
-///     let arg = patter::Args { hash: None, file_path: Some("./cargo.toml".to_string()), action: "pin_file".to_string(), provider: Some("pinata".to_string())};
+///     let arg = patter::Args { hash: None, file_path: Some("./cargo.toml".to_string()), action: "pin_file".to_string(), provider: Some("pinata".to_string()), output: None, profile: None, quorum: None, name: None, tags: vec![], after: None, before: None, status: None, cid_version: None, wrap: false, replication: vec![], provider_options: vec![], cid_base: None };
 ///     let result = patter::run(arg).await.unwrap();
 ///     assert_eq!(result, ());
 /// # })
 /// ```
+pub async fn run(args: Args) -> Result<(), ApiError> {
+    run_with_registry(args, &ProviderRegistry::with_defaults()).await
+}
 
-pub async fn run(args: Args) -> Result<(), &'static str> {
-    let providers: Vec<Box<dyn StorageProvider + Send + Sync>> = if let Some(provider) = args.provider {
-        match provider.as_str() {
-            "pinata" => {
-                vec![Box::new(PinataProvider::new(None, None).unwrap()) as SafeStorage]
-            }
-            "web3" => {
-                vec![Box::new(Web3StorageProvider::new(None).unwrap()) as SafeStorage]
-            }
-            _ => {
-                panic!("Unsupported provider");
-            }
+/// Same as `run`, with providers looked up in `registry` so library users can add their own
+pub async fn run_with_registry(args: Args, registry: &ProviderRegistry) -> Result<(), ApiError> {
+    let file_path = || args.file_path.clone().ok_or_else(|| ApiError::GenericError(format!("Pass the file to {} with --file-path", args.action)));
+    // computing the cid and building car files is done locally and needs no provider
+    match args.action.as_str() {
+        "cid" => {
+            let expected = ExpectedCids::compute(&[file_path()?])?;
+            println!("[patter::cid]:: v0 {} v1 {}", expected.v0, expected.v1.to_string_in(args.cid_base.unwrap_or_default()));
+            return Ok(());
         }
-    } else {
-        vec![Box::new(PinataProvider::new(None, None).unwrap()) as SafeStorage, Box::new(Web3StorageProvider::new(None).unwrap()) as SafeStorage]
+        "car" => {
+            let file_path = file_path()?;
+            let output = args.output.clone().unwrap_or_else(|| {
+                let name = Path::new(&file_path).file_name().map(|name| name.to_string_lossy().to_string());
+                format!("{}.car", name.unwrap_or_else(|| "patter".to_string()))
+            });
+            let root = car::create_car_file(&[file_path], DagOptions::v1(), &output)?;
+            println!("[patter::car]:: wrote {} with root {}", output, root);
+            return Ok(());
+        }
+        "login" => {
+            let provider = args.provider.ok_or_else(|| ApiError::GenericError("Pass the provider to log in to with --provider".to_string()))?;
+            let path = login::login(&provider, args.profile.as_deref(), registry).await?;
+            println!("[patter::login]:: saved {} credentials to {:?}", provider, path);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // checked before any provider is created, so a typo fails without network calls
+    let options = pin_options(&args)?;
+    let profile = match PatterConfig::load()? {
+        Some(config) => config.profile(args.profile.as_deref())?,
+        None if args.profile.is_some() => return Err(ApiError::GenericError(format!("No {} found for profile {:?}", config::CONFIG_FILE_NAME, args.profile))),
+        None => None,
+    };
+    let (profile_providers, configs) = match profile {
+        Some(profile) => {
+            println!("Using profile {}", profile.name);
+            (Some(profile.providers.join(",")).filter(|names| !names.is_empty()), profile.configs)
+        }
+        None => (None, HashMap::new()),
+    };
+    let provider_names = args.provider.or(profile_providers).unwrap_or_else(|| DEFAULT_PROVIDERS.to_string());
+    let providers = registry.create_all(&provider_names, &configs)?;
+    options.warn_unread_extensions(&provider_names.split(',').map(|name| name.trim()).collect::<Vec<&str>>());
+    let policy = match args.quorum {
+        Some(quorum) => success_policy(&quorum, &provider_names, &providers)?,
+        // listing is read only, what the providers that answered hold is worth showing
+        None if args.action == "list" => SuccessPolicy::Any,
+        None => SuccessPolicy::All,
     };
 
     let names = providers.iter().map(|p| p.name()).collect::<Vec<String>>();
     println!("Uploading to the providers: {:?}", names);
 
-    if providers.len() == 0 {
-        return Err("No Valid provider");
+    if providers.is_empty() {
+        return Err(ApiError::GenericError("No Valid provider".to_string()));
+    }
+    let mut metadata = PinMetadata::new();
+    metadata.name = args.name.clone();
+    for tag in args.tags.iter() {
+        metadata = metadata.with_tag_pair(tag)?;
     }
+    let patter_api = PatterApi::new().with_policy(policy);
+    let hash = || args.hash.clone().ok_or_else(|| ApiError::GenericError("Pass the cid with --hash".to_string()));
     match args.action.as_str() {
         "pin_file" => {
             println!("pin files");
-            let patter_api = PatterApi::new();
+            let mut pin_data = PinFileData::new(file_path()?, providers).with_metadata(metadata).with_options(options);
+            if let Some(bars) = progress::progress_bars() {
+                pin_data = pin_data.with_progress(bars);
+            }
 
-            let result: Result<Vec<PinnedObject>, ApiError> = patter_api.pin_file(PinFileData { files: vec![args.file_path.unwrap()], providers }).await;
-            println!("[patter_api.pin_file]:: {:?}", result.unwrap());
+            let report = reported("pin_file", patter_api.pin_file(pin_data).await)?;
+            println!("[patter_api.pin_file]:: {}", report.agreement());
         }
         "pin_json" => {
             println!("pin json");
-            let patter_api = PatterApi::new();
 
-            let result: Result<Vec<PinnedObject>, ApiError> = patter_api.pin_json(PinJsonData { file: args.file_path.unwrap(), providers }).await;
-            println!("[patter_api.pin_json]:: {:?}", result.unwrap());
+            let report = reported("pin_json", patter_api.pin_json(PinJsonData::new(file_path()?, providers).with_metadata(metadata).with_options(options)).await)?;
+            println!("[patter_api.pin_json]:: {}", report.agreement());
+        }
+        "pin_car" => {
+            println!("pin car");
+
+            let report = reported("pin_car", patter_api.pin_car(PinCarData { file: file_path()?, providers }).await)?;
+            println!("[patter_api.pin_car]:: {}", report.agreement());
         }
         "pin_hash" => {
-            println!("....pin hash....");
-            let patter_api = PatterApi::new();
+            let hash = hash()?;
+            println!("....pin hash {}....", print_cid(&hash, args.cid_base));
 
-            let result: Result<Vec<PinByHashResult>, ApiError> = patter_api.pin_by_hash(PinHashData { hash: args.hash.unwrap(), providers }).await;
-            println!("[patter_api.pin_hash]:: {:?}", result.unwrap());
+            reported("pin_hash", patter_api.pin_by_hash(PinHashData::new(hash, providers).with_metadata(metadata)).await)?;
         }
         "unpin" => {
-            println!("....removing cid....");
-            let patter_api = PatterApi::new();
+            let hash = hash()?;
+            println!("....removing cid {}....", print_cid(&hash, args.cid_base));
 
-            let result: Result<(), ApiError> = patter_api.unpin(PinHashData { hash: args.hash.unwrap(), providers }).await;
-            println!("[patter_api.unpin]:: {:?}", result.unwrap());
+            reported("unpin", patter_api.unpin(PinHashData::new(hash, providers)).await)?;
+        }
+        "list" => {
+            let filter = PinFilter { name: metadata.name, tags: metadata.tags, after: args.after, before: args.before, status: args.status };
+            let result = patter_api.list_pins(ListPinsData::new(providers).with_filter(filter)).await;
+            let report = match &result {
+                Ok(report) => Some(report),
+                Err(e) => e.report::<Vec<PinRecord>>(),
+            };
+            if let Some(report) = report {
+                print_listing(report);
+            }
+            result?;
         }
         _ => {
             panic!("Specify what you want to do.\n \
             use `--action pin_file` to pin a file\n
+            use `--action cid` to print the cid of a file without uploading it\n
+            use `--action car` to write a file to a car archive without uploading it\n
+            use `--action pin_car` to upload a car archive\n
+            use `--action list` to list the pins of the providers\n
+            use `--action login --provider pinata` to save the credentials of a provider\n
             ")
         }
     };
     Ok(())
 }
 
+/// Parses `--quorum`, turning the registry names of a required set into the names the
+/// providers created from them report
+fn success_policy(quorum: &str, provider_names: &str, providers: &[SafeStorage]) -> Result<SuccessPolicy, ApiError> {
+    match quorum.parse::<SuccessPolicy>()? {
+        SuccessPolicy::Required(required) => {
+            let created = provider_names.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()).zip(providers.iter());
+            let by_name = created.map(|(name, provider)| (name, provider.name())).collect::<HashMap<&str, String>>();
+            let names = required.iter()
+                .map(|name| by_name.get(name.as_str()).cloned()
+                    .ok_or_else(|| ApiError::GenericError(format!("Required provider {} is not among the providers {}", name, provider_names))))
+                .collect::<Result<Vec<String>, ApiError>>()?;
+            Ok(SuccessPolicy::Required(names))
+        }
+        policy => Ok(policy),
+    }
+}
+
+/// Builds the upload options from `--cid-version`, `--wrap`, `--replicate` and `--provider-option`
+fn pin_options(args: &Args) -> Result<PinOptions, ApiError> {
+    let mut options = PinOptions::new().with_wrap_with_directory(args.wrap);
+    match args.cid_version {
+        Some(0) => options = options.with_cid_version(CidVersion::V0),
+        Some(1) => options = options.with_cid_version(CidVersion::V1),
+        Some(other) => return Err(ApiError::GenericError(format!("Cid version {} does not exist, use 0 or 1", other))),
+        None => {}
+    }
+    for replication in args.replication.iter() {
+        let copies = replication.split_once('=').and_then(|(region, copies)| copies.trim().parse::<u32>().ok().map(|copies| (region.trim(), copies)));
+        match copies {
+            Some((region, copies)) if !region.is_empty() => options = options.with_replication(region, copies),
+            _ => return Err(ApiError::GenericError(format!("Replication {} is not written as REGION=COUNT", replication))),
+        }
+    }
+    for option in args.provider_options.iter() {
+        let parts = option.split_once('=').and_then(|(key, value)| key.split_once('.').map(|(provider, key)| (provider, key, value)));
+        match parts {
+            Some((provider, key, value)) if !provider.is_empty() && !key.is_empty() => {
+                // numbers and booleans are sent as such, anything else as text
+                let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+                options = options.with_extension(provider, key, value);
+            }
+            _ => return Err(ApiError::GenericError(format!("Provider option {} is not written as provider.key=value", option))),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_cid(cid: &str) -> Result<Cid, String> {
+    cid.parse().map_err(|e: ApiError| e.to_string())
+}
+
+fn parse_status(status: &str) -> Result<JobStatus, String> {
+    status.parse().map_err(|e: ApiError| e.to_string())
+}
+
+/// Accepts a `YYYY-MM-DD` date or an ISO 8601 timestamp starting with one
+fn parse_date(date: &str) -> Result<String, String> {
+    match utils::parse_timestamp(date) {
+        Some(_) => Ok(date.to_string()),
+        None => Err(format!("{} is not a YYYY-MM-DD date or RFC 3339 timestamp", date)),
+    }
+}
+
+fn parse_multibase(base: &str) -> Result<Multibase, String> {
+    base.parse().map_err(|e: ApiError| e.to_string())
+}
+
+/// `cid` in `base`, or in its usual form when no base was asked for
+fn print_cid(cid: &Cid, base: Option<Multibase>) -> String {
+    match base {
+        Some(base) => cid.to_string_in(base),
+        None => cid.to_string(),
+    }
+}
+
+/// Prints the report of an operation, also when it did not meet the success policy so the
+/// providers that did succeed are shown
+fn reported<T: Debug + 'static>(action: &str, result: Result<FanOutReport<T>, ApiError>) -> Result<FanOutReport<T>, ApiError> {
+    match result {
+        Ok(report) => {
+            print_report(action, &report);
+            Ok(report)
+        }
+        Err(e) => {
+            if let Some(report) = e.report::<T>() {
+                print_report(action, report);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Prints how many pins every provider listed, then each pin with the providers holding it
+fn print_listing(report: &FanOutReport<Vec<PinRecord>>) {
+    for outcome in report.outcomes.iter() {
+        match &outcome.result {
+            Ok(records) => println!("[patter_api.list]:: {} listed {} pin(s) in {:?}", outcome.provider, records.len(), outcome.duration),
+            Err(e) => println!("[patter_api.list]:: {} failed in {:?} after {} attempt(s): {}", outcome.provider, outcome.duration, outcome.attempts, e),
+        }
+    }
+    for pin in report.merged() {
+        println!("[patter_api.list]:: {}", pin);
+    }
+}
+
+/// Prints what every provider answered, one line each
+fn print_report<T: Debug>(action: &str, report: &FanOutReport<T>) {
+    for outcome in report.outcomes.iter() {
+        match &outcome.result {
+            Ok(value) => println!("[patter_api.{}]:: {} succeeded in {:?} after {} attempt(s): {:?}", action, outcome.provider, outcome.duration, outcome.attempts, value),
+            Err(e) => println!("[patter_api.{}]:: {} failed in {:?} after {} attempt(s): {}", action, outcome.provider, outcome.duration, outcome.attempts, e),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,128 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use crate::config::PatterConfig;
+use crate::credentials;
+use crate::errors::ApiError;
+use crate::registry::{ConfigField, ProviderConfig, ProviderRegistry};
+use crate::utils::trim_newline;
+
+/// Profile credentials are saved to when neither `--profile` nor the config file names one
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Asks for the credentials of `provider`, checks them against the provider and saves them
+/// to the `patter.toml` of the user config dir. Returns the path of the config file written
+pub async fn login(provider: &str, profile: Option<&str>, registry: &ProviderRegistry) -> Result<PathBuf, ApiError> {
+    let schema = registry.schema(provider)
+        .ok_or_else(|| ApiError::UnknownProvider { name: provider.to_string(), valid: registry.names().join(", ") })?
+        .to_vec();
+    // a patter.toml in the working directory may be checked in, so secrets never go there
+    let path = PatterConfig::user_path()
+        .ok_or_else(|| ApiError::GenericError("Could not find a directory to save the config file in".to_string()))?;
+    if let Some(project) = PatterConfig::find().filter(|found| *found != path) {
+        println!("{:?} is read before {:?}, the credentials saved are only used where it is not found", project, path);
+    }
+
+    println!("Logging in to {}, leave a value empty to skip it", provider);
+    let config = prompt_config(&schema, &mut io::stdin().lock(), |prompt| rpassword::prompt_password(prompt))?;
+
+    // the env or a credential helper could otherwise stand in for what was typed
+    let created = credentials::config_only(|| registry.create(provider, &config))?;
+    created.init().await?;
+    save_credentials(&path, profile, provider, &config)?;
+    Ok(path)
+}
+
+/// Asks for every required or secret field of `schema`. Secrets are read through `read_secret`
+/// so they are not echoed, the other values are read from `input`
+pub fn prompt_config<R, F>(schema: &[ConfigField], input: &mut R, mut read_secret: F) -> Result<ProviderConfig, ApiError>
+    where R: BufRead, F: FnMut(&str) -> io::Result<String>
+{
+    let mut config = ProviderConfig::new();
+    for field in schema.iter().filter(|field| field.required || field.secret) {
+        let prompt = format!("{}: ", field.description);
+        let mut value = if field.secret {
+            read_secret(&prompt)?
+        } else {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            let mut value = String::new();
+            input.read_line(&mut value)?;
+            value
+        };
+        let value = trim_newline(&mut value).trim().to_string();
+        if !value.is_empty() {
+            config.insert(field.name.clone(), value);
+        }
+    }
+    Ok(config)
+}
+
+/// Writes `config` to the table of `provider` in `profile`, adding the provider to the profile.
+/// Without a profile the config file's default profile is used, or `default` when it has none.
+/// The file is only readable by its owner
+pub fn save_credentials(path: &Path, profile: Option<&str>, provider: &str, config: &ProviderConfig) -> Result<(), ApiError> {
+    let mut document = if path.is_file() {
+        fs::read_to_string(path)?.parse::<Table>()
+            .map_err(|e| ApiError::GenericError(format!("Invalid config file {:?}: {}", path, e)))?
+    } else {
+        Table::new()
+    };
+
+    let default_profile = document.get("default_profile").and_then(|value| value.as_str()).map(|name| name.to_string());
+    let profile = profile.map(|name| name.to_string())
+        .or(default_profile.clone())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    if default_profile.is_none() {
+        document.insert("default_profile".to_string(), Value::String(profile.clone()));
+    }
+
+    let profiles = table(&mut document, "profiles", path)?;
+    let profile_table = table(profiles, &profile, path)?;
+    let providers = profile_table.entry("providers").or_insert_with(|| Value::Array(vec![]));
+    match providers.as_array_mut() {
+        Some(providers) if !providers.iter().any(|name| name.as_str() == Some(provider)) => providers.push(Value::String(provider.to_string())),
+        Some(_) => {}
+        None => return Err(ApiError::GenericError(format!("providers of profile {} in {:?} is not a list", profile, path))),
+    }
+    let provider_table = table(profile_table, provider, path)?;
+    for (key, value) in config.iter() {
+        provider_table.insert(key.clone(), Value::String(value.clone()));
+    }
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    // written next to the config and renamed over it, so a failed write leaves the old file
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies to new files, so tighten a leftover temp file too
+        if temp.exists() {
+            fs::set_permissions(&temp, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let written = options.open(&temp)
+        .and_then(|mut file| file.write_all(document.to_string().as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// The table stored under `key`, created when missing
+fn table<'a>(parent: &'a mut Table, key: &str, path: &Path) -> Result<&'a mut Table, ApiError> {
+    parent.entry(key.to_string())
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| ApiError::GenericError(format!("{} in {:?} is not a table", key, path)))
+}
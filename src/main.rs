@@ -1,6 +1,6 @@
 use std::{io, process};
 use clap::Parser;
-use patter::Args;
+use patter::{ApiError, Args};
 use dotenv::dotenv;
 //  cargo run -- --action pin_file --file-path ./cargo.toml --provider pinata
 
@@ -11,7 +11,9 @@ async fn main() -> Result<(), io::Error> {
     dbg!(&arg);
     if let Err(e) = patter::run(arg).await {
         println!("Application error {e}");
-        process::exit(1);
+        // a distinct code so scripts can tell providers failing from patter failing
+        let code = if matches!(e, ApiError::PolicyNotMet { .. }) { 2 } else { 1 };
+        process::exit(code);
     } else {
         println!("Files backed up ✅ ✅  ");
     }
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Something that happened while uploading to one provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// The upload to `provider` started, `total_size` bytes are about to be sent
+    Started { provider: String, total_size: u64 },
+    /// More bytes of `file` were sent to `provider`
+    Sent { provider: String, file: String, file_sent: u64, file_size: u64, total_sent: u64, total_size: u64 },
+    Finished { provider: String, succeeded: bool },
+}
+
+/// Receives the progress of every provider of an upload, called from the upload tasks
+pub type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Callback that forwards every event to the returned channel, for callers that would
+/// rather poll than be called
+pub fn progress_channel() -> (ProgressCallback, UnboundedReceiver<Progress>) {
+    let (sender, receiver) = unbounded_channel();
+    let callback: ProgressCallback = Arc::new(move |progress| {
+        // the receiver going away only means nobody is listening anymore
+        let _ = sender.send(progress);
+    });
+    (callback, receiver)
+}
+
+/// Reports the bytes one provider has sent, shared by every file of the upload
+#[derive(Clone)]
+pub struct ProgressReporter {
+    provider: String,
+    total_size: u64,
+    total_sent: Arc<AtomicU64>,
+    callback: ProgressCallback,
+}
+
+impl ProgressReporter {
+    /// Creates a reporter for an upload of `total_size` bytes, `started` reports it once the
+    /// upload actually begins
+    pub fn new<S: Into<String>>(provider: S, total_size: u64, callback: ProgressCallback) -> Self {
+        ProgressReporter { provider: provider.into(), total_size, total_sent: Arc::new(AtomicU64::new(0)), callback }
+    }
+
+    /// Reports the upload started, once the provider got its turn to send
+    pub fn started(&self) {
+        (self.callback)(Progress::Started { provider: self.provider.clone(), total_size: self.total_size });
+    }
+
+    /// Reports `bytes` more bytes of `file` were sent, `file_sent` of them so far
+    pub fn sent(&self, file: &str, file_sent: u64, file_size: u64, bytes: u64) {
+        let total_sent = self.total_sent.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        (self.callback)(Progress::Sent {
+            provider: self.provider.clone(),
+            file: file.to_string(),
+            file_sent,
+            file_size,
+            total_sent,
+            total_size: self.total_size,
+        });
+    }
+
+    /// Bytes reported sent so far, across every file
+    pub fn total_sent(&self) -> u64 {
+        self.total_sent.load(Ordering::Relaxed)
+    }
+
+    /// Takes back the bytes reported since `total_sent` returned `to`, for uploads that are retried
+    pub fn rewind(&self, to: u64) {
+        self.total_sent.store(to, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self, succeeded: bool) {
+        (self.callback)(Progress::Finished { provider: self.provider.clone(), succeeded });
+    }
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressReporter")
+            .field("provider", &self.provider)
+            .field("total_size", &self.total_size)
+            .field("total_sent", &self.total_sent.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Callback drawing one progress bar per provider with throughput and ETA on stdout.
+/// Returns `None` when stdout is not a terminal, so piped output stays free of escape codes
+pub fn progress_bars() -> Option<ProgressCallback> {
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let style = ProgressStyle::with_template("{prefix:24} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta} {msg}")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+    let by_provider: Mutex<HashMap<String, ProgressBar>> = Mutex::new(HashMap::new());
+
+    Some(Arc::new(move |progress| {
+        let mut by_provider = by_provider.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match progress {
+            Progress::Started { provider, total_size } => {
+                let bar = bars.add(ProgressBar::new(total_size).with_style(style.clone()).with_prefix(provider.clone()));
+                by_provider.insert(provider, bar);
+            }
+            Progress::Sent { provider, total_sent, .. } => {
+                if let Some(bar) = by_provider.get(&provider) {
+                    bar.set_position(total_sent);
+                }
+            }
+            Progress::Finished { provider, succeeded } => {
+                if let Some(bar) = by_provider.remove(&provider) {
+                    bar.finish_with_message(if succeeded { "done" } else { "failed" });
+                }
+            }
+        }
+    }))
+}
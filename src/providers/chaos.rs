@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use crate::api::data::{PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinRecord, PinnedObject, UnPin};
+use crate::cid::{Cid, CidVersion, DAG_PB, RAW};
+use crate::data::{SafeStorage, StorageProvider};
+use crate::errors::ApiError;
+
+/// What a `ChaosProvider` does to the calls it forwards
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    /// Delay added before every call
+    pub latency: Duration,
+    /// Extra random delay of up to this much added on top of `latency`
+    pub jitter: Duration,
+    /// Probability, from 0 to 1, that a call fails without reaching the wrapped provider
+    pub failure_rate: f64,
+    /// Status codes injected failures report, one is picked at random for each failure
+    pub error_codes: Vec<u16>,
+    /// Probability, from 0 to 1, that a successful pin answers with a cid of other content
+    pub wrong_cid_rate: f64,
+    /// Seed for the random choices, so a failing run can be replayed
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            failure_rate: 0.0,
+            error_codes: vec![500],
+            wrong_cid_rate: 0.0,
+            seed: None,
+        }
+    }
+}
+
+/// Wraps a provider and injects latency, failures and wrong cids into its answers, to check
+/// how `PatterApi` copes when one provider is slow or broken
+pub struct ChaosProvider {
+    inner: SafeStorage,
+    config: ChaosConfig,
+    rng: Mutex<StdRng>,
+}
+
+impl ChaosProvider {
+    pub fn new(inner: SafeStorage, config: ChaosConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        ChaosProvider { inner, config, rng: Mutex::new(rng) }
+    }
+
+    /// Waits for the configured latency, then decides whether the call fails
+    async fn disrupt(&self) -> Result<(), ApiError> {
+        let (delay, failure) = {
+            let mut rng = self.rng();
+            let jitter = if self.config.jitter.is_zero() { Duration::ZERO } else { self.config.jitter.mul_f64(rng.gen::<f64>()) };
+            let failure = if rng.gen_bool(self.config.failure_rate.clamp(0.0, 1.0)) {
+                let codes = &self.config.error_codes;
+                Some(if codes.is_empty() { 500 } else { codes[rng.gen_range(0..codes.len())] })
+            } else {
+                None
+            };
+            (self.config.latency + jitter, failure)
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        match failure {
+            Some(status) => Err(ApiError::HttpStatus { status, message: format!("Failure injected into {}", self.inner.name()) }),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces `cid` with the cid of random bytes of the same version when the dice say so
+    fn corrupt(&self, cid: String) -> String {
+        let mut rng = self.rng();
+        if !rng.gen_bool(self.config.wrong_cid_rate.clamp(0.0, 1.0)) {
+            return cid;
+        }
+        let mut block = [0u8; 32];
+        rng.fill_bytes(&mut block);
+        let wrong = if cid.starts_with("Qm") {
+            Cid::for_block(CidVersion::V0, DAG_PB, &block)
+        } else {
+            Cid::for_block(CidVersion::V1, RAW, &block)
+        };
+        println!("[ChaosProvider] answering {} instead of {}", wrong, cid);
+        wrong.to_string()
+    }
+
+    fn corrupt_pinned(&self, result: Result<PinnedObject, ApiError>) -> Result<PinnedObject, ApiError> {
+        result.map(|pinned| PinnedObject { ipfs_hash: self.corrupt(pinned.ipfs_hash), ..pinned })
+    }
+
+    fn rng(&self) -> std::sync::MutexGuard<'_, StdRng> {
+        self.rng.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageProvider for ChaosProvider {
+    fn name(&self) -> String {
+        format!("Chaos {}", self.inner.name())
+    }
+
+    async fn init(&self) -> Result<(), ApiError> {
+        self.disrupt().await?;
+        self.inner.init().await
+    }
+
+    fn api_url(&self) -> String {
+        self.inner.api_url()
+    }
+
+    async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.disrupt().await?;
+        self.corrupt_pinned(self.inner.pin_file(pin_data).await)
+    }
+
+    async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
+        self.disrupt().await?;
+        self.corrupt_pinned(self.inner.pin_json(pin_data).await)
+    }
+
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        self.disrupt().await?;
+        self.corrupt_pinned(self.inner.pin_car(pin_data).await)
+    }
+
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        self.disrupt().await?;
+        let result = self.inner.pin_by_hash(pin_data).await?;
+        Ok(PinByHashResult { ipfs_hash: self.corrupt(result.ipfs_hash), ..result })
+    }
+
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.disrupt().await?;
+        self.corrupt_pinned(self.inner.pin_directory(pin_data).await)
+    }
+
+    async fn unpin(&self, options: UnPin) -> Result<(), ApiError> {
+        self.disrupt().await?;
+        self.inner.unpin(options).await
+    }
+
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        self.disrupt().await?;
+        self.inner.list_pins(filter).await
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use async_trait::async_trait;
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response, StatusCode};
+use reqwest::multipart::{Form, Part};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use crate::api::data::{JobStatus, PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinOptions, PinRecord, PinnedObject, UnPin};
+use crate::cid::CidVersion;
+use crate::data::StorageProvider;
+use crate::errors::{ApiError, Error};
+use crate::progress::ProgressReporter;
+use crate::retry::{is_transient, RetryPolicy};
+use crate::utils::{file_part, transform_file_to_form};
+
+/// Address of the RPC api of a Kubo node started with the default config
+pub const DEFAULT_KUBO_API_URL: &str = "http://127.0.0.1:5001";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct KuboApiError {
+    message: String,
+}
+
+impl KuboApiError {
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+/// One line of the newline delimited json `/api/v0/add` streams back
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddedEntry {
+    hash: String,
+    size: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinList {
+    keys: HashMap<String, PinType>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinType {
+    #[serde(rename = "Type")]
+    kind: String,
+    /// Only sent when asked for with `names=true`, by kubo 0.26 or later
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinChange {
+    pins: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DagImport {
+    root: Option<DagImportRoot>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DagImportRoot {
+    cid: DagLink,
+    pin_error_msg: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DagLink {
+    #[serde(rename = "/")]
+    cid: String,
+}
+
+/// Provider that pins to a self-hosted Kubo (go-ipfs) node through its HTTP RPC api
+#[derive(Debug)]
+pub struct KuboProvider {
+    pub name: String,
+    pub api_url: String,
+    pub retry: RetryPolicy,
+    client: Client
+}
+
+impl KuboProvider {
+    pub fn new(api_url: Option<String>) -> Result<KuboProvider, Error> {
+        let api_url = api_url
+            .or_else(|| std::env::var("KUBO_API_URL").ok())
+            .unwrap_or_else(|| DEFAULT_KUBO_API_URL.to_string());
+        let client = ClientBuilder::new().build()?;
+
+        Ok(KuboProvider {
+            name: "Kubo Provider".to_string(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            retry: RetryPolicy::default(),
+            client
+        })
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends the request through the retry policy. Kubo answers every failed command with a 500,
+    /// so only the other statuses, such as those of a proxy in front of the node, are retried
+    async fn send<F>(&self, progress: Option<&ProgressReporter>, request: F) -> Result<Response, ApiError>
+        where F: FnMut() -> Result<RequestBuilder, ApiError>
+    {
+        self.retry.send_when(&self.name, progress, |status| status != StatusCode::INTERNAL_SERVER_ERROR && is_transient(status), request).await
+    }
+
+    /// Lists the pins of the node, restricted to `cid` when one is given
+    pub async fn pin_ls(&self, cid: Option<&str>) -> Result<HashMap<String, String>, ApiError> {
+        let response = self.send(None, || {
+            let mut request = self.client.post(format!("{}{}", &self.api_url, "/api/v0/pin/ls"));
+            if let Some(cid) = cid {
+                request = request.query(&[("arg", cid)]);
+            }
+            Ok(request)
+        }).await?;
+
+        let pins = self.parse_result::<PinList>(response).await?;
+        Ok(pins.keys.into_iter().map(|(cid, pin)| (cid, pin.kind)).collect())
+    }
+
+    /// Adds the files of the form built by `form`, built again for every attempt
+    async fn add<F>(&self, form: F, options: &PinOptions, progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError>
+        where F: Fn() -> Result<Form, ApiError>
+    {
+        options.warn_unsupported("kubo", &["cid_version", "wrap_with_directory"]);
+        let mut query = vec![("pin".to_string(), "true".to_string())];
+        if let Some(cid_version) = options.cid_version {
+            query.push(("cid-version".to_string(), if cid_version == CidVersion::V0 { "0" } else { "1" }.to_string()));
+        }
+        if options.wrap_with_directory {
+            query.push(("wrap-with-directory".to_string(), "true".to_string()));
+        }
+        for (key, value) in options.extension("kubo").into_iter().flatten() {
+            let value = match value {
+                serde_json::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            query.push((key.clone(), value));
+        }
+        let response = self.send(progress, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/add")).query(&query).multipart(form()?))
+        }).await?;
+
+        if !response.status().is_success() {
+            return Err(self.parse_error(response).await);
+        }
+        // the root is the last entry streamed back, after all the files and directories below it
+        let body = response.text().await?;
+        let mut root = None;
+        for line in body.lines().filter(|line| !line.trim().is_empty()) {
+            let entry = serde_json::from_str::<AddedEntry>(line)
+                .map_err(|e| ApiError::GenericError(format!("Could not parse kubo add response: {}", e)))?;
+            root = Some(entry);
+        }
+
+        match root {
+            Some(entry) => Ok(PinnedObject {
+                ipfs_hash: entry.hash,
+                pin_size: entry.size.and_then(|size| size.parse().ok()).unwrap_or(0),
+                timestamp: "".to_string(),
+                verified: None
+            }),
+            None => Err(ApiError::GenericError("Kubo did not add any file".to_string())),
+        }
+    }
+
+    async fn parse_result<R>(&self, response: Response) -> Result<R, ApiError>
+        where R: DeserializeOwned
+    {
+        if response.status().is_success() {
+            let result = response.json::<R>().await?;
+            Ok(result)
+        } else {
+            Err(self.parse_error(response).await)
+        }
+    }
+
+    async fn parse_error(&self, response: Response) -> ApiError {
+        match response.json::<KuboApiError>().await {
+            Ok(error) => ApiError::GenericError(error.message()),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for KuboProvider {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Kubo has no credentials, so this only checks the rpc api answers
+    async fn init(&self) -> Result<(), ApiError> {
+        let response = self.send(None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/version")))
+        }).await?;
+
+        self.parse_result::<serde_json::Value>(response).await.map(|_| ())
+    }
+
+    fn api_url(&self) -> String {
+        self.api_url.clone()
+    }
+
+    async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        pin_data.metadata.warn_dropped("kubo");
+        // kubo answers with the last of several top level entries unless they are wrapped,
+        // while the expected cid is that of a directory holding them all
+        let mut options = pin_data.options.clone();
+        if pin_data.files.len() > 1 {
+            options.wrap_with_directory = true;
+        }
+        self.add(|| transform_file_to_form(&pin_data), &options, pin_data.progress.as_ref()).await
+    }
+
+    async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
+        pin_data.metadata.warn_dropped("kubo");
+        let file = fs::read_to_string(&pin_data.file)?;
+        serde_json::from_str::<serde_json::Value>(file.as_str())
+            .map_err(|e| ApiError::GenericError(format!("Could not parse json file: {}", e)))?;
+
+        let file_name = Path::new(&pin_data.file).file_name().map(|name| name.to_string_lossy().to_string());
+        let file_name = file_name.unwrap_or_else(|| "data.json".to_string());
+        self.add(|| Ok(Form::new().part("file", Part::text(file.clone()).file_name(file_name.clone()))), &pin_data.options, None).await
+    }
+
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        let response = self.send(None, || {
+            let part = file_part(Path::new(&pin_data.file), None)?.file_name("upload.car");
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/dag/import"))
+                .query(&[("pin-roots", "true")])
+                .multipart(Form::new().part("file", part)))
+        }).await?;
+
+        let import = self.parse_result::<DagImport>(response).await?;
+        match import.root {
+            Some(DagImportRoot { pin_error_msg: Some(error), .. }) if !error.is_empty() => Err(ApiError::GenericError(error)),
+            Some(root) => Ok(PinnedObject { ipfs_hash: root.cid.cid, pin_size: 0, timestamp: "".to_string(), verified: None }),
+            None => Err(ApiError::GenericError("Kubo did not report the root of the car".to_string())),
+        }
+    }
+
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        pin_data.metadata.warn_tags_dropped("kubo");
+        let mut query = vec![("arg", pin_data.hash_to_pin.to_string())];
+        // pin names need kubo 0.26 or later, older nodes ignore the argument
+        if let Some(name) = pin_data.metadata.name.as_ref() {
+            query.push(("name", name.clone()));
+        }
+        let response = self.send(None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/pin/add")).query(&query))
+        }).await?;
+        let added = self.parse_result::<PinChange>(response).await?;
+        let cid = added.pins.into_iter().next().unwrap_or_else(|| pin_data.hash_to_pin.to_string());
+
+        let pins = self.pin_ls(Some(&cid)).await?;
+        let status = if pins.is_empty() { JobStatus::Pinning } else { JobStatus::Pinned };
+        Ok(PinByHashResult { id: cid.clone(), ipfs_hash: cid, status, name: None })
+    }
+
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        if let Some(file) = pin_data.files.iter().find(|file| !Path::new(file).is_dir()) {
+            return Err(ApiError::GenericError(format!("{} is not a directory", file)));
+        }
+        self.pin_file(pin_data).await
+    }
+
+    async fn unpin(&self, data: UnPin) -> Result<(), ApiError> {
+        let response = self.send(None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/pin/rm")).query(&[("arg", &data.cid)]))
+        }).await?;
+
+        self.parse_result::<PinChange>(response).await?;
+        Ok(())
+    }
+
+    /// Lists the recursive pins, the ones `pin_file` and `pin_by_hash` make. Kubo keeps no date,
+    /// size or tags for a pin, so a date range filters out every pin
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let response = self.send(None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/api/v0/pin/ls")).query(&[("type", "recursive"), ("names", "true")]))
+        }).await?;
+
+        let pins = self.parse_result::<PinList>(response).await?;
+        let mut records = pins.keys.into_iter()
+            .map(|(cid, pin)| {
+                let mut record = PinRecord::new(cid, JobStatus::Pinned);
+                record.metadata.name = pin.name.filter(|name| !name.is_empty());
+                record
+            })
+            .filter(|record| filter.matches(record))
+            .collect::<Vec<PinRecord>>();
+        records.sort_by(|a, b| a.cid.cmp(&b.cid));
+        Ok(records)
+    }
+}
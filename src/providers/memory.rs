@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use crate::api::data::{JobStatus, PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinMetadata, PinRecord, PinnedObject, UnPin};
+use crate::car::read_root;
+use crate::cid::CidVersion;
+use crate::data::StorageProvider;
+use crate::errors::ApiError;
+use crate::unixfs::{DagBuilder, DagOptions};
+use crate::utils::{total_file_size, utc_timestamp};
+
+/// Provider that keeps its pins in memory and works out cids locally, so code using patter
+/// can be tested without credentials or network access
+#[derive(Debug)]
+pub struct MemoryProvider {
+    pub name: String,
+    options: DagOptions,
+    pins: Mutex<HashMap<String, (PinnedObject, PinMetadata)>>,
+}
+
+impl MemoryProvider {
+    /// Creates an empty provider answering with CIDv0, like Pinata
+    pub fn new() -> Self {
+        MemoryProvider::with_options(DagOptions::v0())
+    }
+
+    /// Creates an empty provider that builds its dags with `options`
+    pub fn with_options(options: DagOptions) -> Self {
+        MemoryProvider { name: "Memory Provider".to_string(), options, pins: Mutex::new(HashMap::new()) }
+    }
+
+    /// Every pin currently held, sorted by cid
+    pub fn pins(&self) -> Vec<PinnedObject> {
+        let mut pins = self.lock().values().map(|(pinned, _)| pinned.clone()).collect::<Vec<PinnedObject>>();
+        pins.sort_by(|a, b| a.ipfs_hash.cmp(&b.ipfs_hash));
+        pins
+    }
+
+    pub fn is_pinned(&self, cid: &str) -> bool {
+        self.lock().contains_key(cid)
+    }
+
+    /// Name and tags the pin of `cid` was stored with
+    pub fn metadata(&self, cid: &str) -> Option<PinMetadata> {
+        self.lock().get(cid).map(|(_, metadata)| metadata.clone())
+    }
+
+    fn pin(&self, cid: String, pin_size: u64, metadata: PinMetadata) -> PinnedObject {
+        let pinned = PinnedObject { ipfs_hash: cid.clone(), pin_size, timestamp: utc_timestamp(), verified: None };
+        self.lock().insert(cid, (pinned.clone(), metadata));
+        pinned
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (PinnedObject, PinMetadata)>> {
+        // a panic while holding the lock cannot leave the map half updated, so keep using it
+        self.pins.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for MemoryProvider {
+    fn default() -> Self {
+        MemoryProvider::new()
+    }
+}
+
+#[async_trait]
+impl StorageProvider for MemoryProvider {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn init(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    fn api_url(&self) -> String {
+        "memory://".to_string()
+    }
+
+    async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        pin_data.options.warn_unsupported(&self.name, &["cid_version", "wrap_with_directory"]);
+        let mut options = DagOptions { wrap_with_directory: pin_data.options.wrap_with_directory, ..self.options.clone() };
+        if let Some(cid_version) = pin_data.options.cid_version {
+            // like kubo, cid version 1 implies raw leaves and version 0 rules them out
+            options.raw_leaves = cid_version == CidVersion::V1;
+            options.cid_version = cid_version;
+        }
+        let cid = DagBuilder::new(options).root_cid(&pin_data.files)?;
+        if let Some(progress) = pin_data.progress.as_ref() {
+            for file in pin_data.files.iter() {
+                let size = total_file_size(std::slice::from_ref(file))?;
+                progress.sent(file, size, size, size);
+            }
+        }
+        Ok(self.pin(cid.to_string(), total_file_size(&pin_data.files)?, pin_data.metadata))
+    }
+
+    async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
+        let file = std::fs::read_to_string(&pin_data.file)?;
+        serde_json::from_str::<serde_json::Value>(file.as_str())
+            .map_err(|e| ApiError::GenericError(format!("Could not parse json file: {}", e)))?;
+        self.pin_file(PinByFile::new(vec![pin_data.file]).with_metadata(pin_data.metadata).with_options(pin_data.options)).await
+    }
+
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        let root = read_root(&pin_data.file)?;
+        Ok(self.pin(root.to_string(), std::fs::metadata(&pin_data.file)?.len(), PinMetadata::default()))
+    }
+
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        let name = pin_data.metadata.name.clone();
+        let pinned = self.pin(pin_data.hash_to_pin.to_string(), 0, pin_data.metadata);
+        Ok(PinByHashResult { id: pinned.ipfs_hash.clone(), ipfs_hash: pinned.ipfs_hash, status: JobStatus::Pinned, name })
+    }
+
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.pin_file(pin_data).await
+    }
+
+    async fn unpin(&self, data: UnPin) -> Result<(), ApiError> {
+        match self.lock().remove(&data.cid.to_string()) {
+            Some(_) => Ok(()),
+            None => Err(ApiError::GenericError(format!("CID {} is not pinned", &data.cid))),
+        }
+    }
+
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let mut records = self.lock().values()
+            .map(|(pinned, metadata)| PinRecord {
+                cid: pinned.ipfs_hash.clone(),
+                size: Some(pinned.pin_size),
+                date: Some(pinned.timestamp.clone()),
+                status: JobStatus::Pinned,
+                metadata: metadata.clone(),
+            })
+            .filter(|record| filter.matches(record))
+            .collect::<Vec<PinRecord>>();
+        records.sort_by(|a, b| a.cid.cmp(&b.cid));
+        Ok(records)
+    }
+}
@@ -1,2 +1,7 @@
+pub mod chaos;
+pub mod kubo;
+pub mod memory;
 pub mod pinata;
+pub mod pinning_service;
+pub mod s3_compatible;
 pub mod web3_storage;
\ No newline at end of file
@@ -1,24 +1,187 @@
-use std::{fs, io};
+use std::fs;
+use std::path::Path;
 use async_trait::async_trait;
-use reqwest::{Client, ClientBuilder, Response};
-use reqwest::header::HeaderMap;
+use reqwest::{Client, ClientBuilder, Response, StatusCode};
+use reqwest::multipart::Form;
+use reqwest::header::{HeaderMap, AUTHORIZATION};
 
 use serde::de::DeserializeOwned;
-use crate::api::data::{PinnedObject, PinByFile, PinByJson, PinByHash, PinByHashResult, UnPin};
+use std::collections::BTreeMap;
+use serde_derive::Serialize;
+use crate::api::data::{JobStatus, PinnedObject, PinByCar, PinByFile, PinByJson, PinByHash, PinByHashResult, PinFilter, PinMetadata, PinOptions, PinRecord, UnPin};
+use crate::cid::CidVersion;
 use crate::data::StorageProvider;
-use crate::errors::{ApiError, Error};
+use crate::credentials::{CredentialResolver, Secret};
+use crate::errors::ApiError;
+use crate::retry::RetryPolicy;
 use crate::utils;
 use serde::Deserialize;
-use crate::utils::transform_file_to_form;
+use crate::utils::{file_part, transform_file_to_form};
+
+/// `pinataMetadata` sent along with a pin
+#[derive(Serialize, Debug)]
+struct PinataMetadata<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    keyvalues: &'a BTreeMap<String, String>,
+}
+
+impl<'a> From<&'a PinMetadata> for PinataMetadata<'a> {
+    fn from(metadata: &'a PinMetadata) -> Self {
+        PinataMetadata { name: metadata.name.as_deref(), keyvalues: &metadata.tags }
+    }
+}
+
+/// `pinataOptions` sent along with an upload, `None` when no option applies to Pinata
+fn pinata_options(options: &PinOptions) -> Option<serde_json::Value> {
+    let mut pinata = serde_json::Map::new();
+    if let Some(cid_version) = options.cid_version {
+        pinata.insert("cidVersion".to_string(), serde_json::json!(if cid_version == CidVersion::V0 { 0 } else { 1 }));
+    }
+    if options.wrap_with_directory {
+        pinata.insert("wrapWithDirectory".to_string(), serde_json::json!(true));
+    }
+    if !options.replication.is_empty() {
+        let regions = options.replication.iter()
+            .map(|(region, copies)| serde_json::json!({ "id": region, "desiredReplicationCount": copies }))
+            .collect::<Vec<serde_json::Value>>();
+        pinata.insert("customPinPolicy".to_string(), serde_json::json!({ "regions": regions }));
+    }
+    if let Some(extension) = options.extension("pinata") {
+        pinata.extend(extension.clone());
+    }
+    if pinata.is_empty() { None } else { Some(serde_json::Value::Object(pinata)) }
+}
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct PinataApiError {
-    error: String
+    error: PinataErrorBody
+}
+
+/// Older endpoints answer with a plain message, newer ones with a reason code and details
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PinataErrorBody {
+    Message(String),
+    Detailed { reason: String, details: Option<String> },
 }
 
 impl PinataApiError {
     pub fn message(&self) -> String {
-        self.error.clone()
+        match &self.error {
+            PinataErrorBody::Message(message) => message.clone(),
+            PinataErrorBody::Detailed { reason, details: Some(details) } => format!("{}: {}", reason, details),
+            PinataErrorBody::Detailed { reason, details: None } => reason.clone(),
+        }
+    }
+
+    /// Whether the key was refused for lacking `scope`, other refusals are not about scopes
+    fn is_missing_scope(&self, scope: &str) -> bool {
+        match &self.error {
+            PinataErrorBody::Detailed { reason, details } => {
+                reason == "NO_SCOPES_FOUND" || details.as_deref().map(|details| details.contains(scope)).unwrap_or(false)
+            }
+            PinataErrorBody::Message(_) => false,
+        }
+    }
+}
+
+/// Scopes a scoped Pinata key needs for each call
+const SCOPE_PIN_FILE: &str = "pinning.pinFileToIPFS";
+const SCOPE_PIN_JSON: &str = "pinning.pinJSONToIPFS";
+const SCOPE_PIN_BY_HASH: &str = "pinning.pinByHash";
+const SCOPE_UNPIN: &str = "pinning.unpin";
+const SCOPE_FILES_WRITE: &str = "files.write";
+const SCOPE_TEST_AUTHENTICATION: &str = "data.testAuthentication";
+const SCOPE_PIN_LIST: &str = "data.pinList";
+const SCOPE_PIN_JOBS: &str = "pinning.pinJobs";
+
+/// Most rows `/data/pinList` and `/pinning/pinJobs` return in one page
+const PIN_LIST_PAGE_LIMIT: usize = 1000;
+
+/// Car imports go through the v3 upload api, which lives on its own host
+const PINATA_UPLOADS_URL: &str = "https://uploads.pinata.cloud";
+
+#[derive(Deserialize, Debug)]
+struct PinataUpload {
+    data: PinataUploadData,
+}
+
+#[derive(Deserialize, Debug)]
+struct PinataUploadData {
+    cid: String,
+    size: u64,
+    created_at: String,
+}
+
+/// One page of `/data/pinList`
+#[derive(Deserialize, Debug)]
+struct PinataPinList {
+    count: usize,
+    rows: Vec<PinataPin>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PinataPin {
+    ipfs_pin_hash: String,
+    size: Option<u64>,
+    date_pinned: Option<String>,
+    metadata: Option<PinataPinMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PinataPinMetadata {
+    name: Option<String>,
+    /// Values can be numbers or dates as well as text
+    keyvalues: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Keyvalues come back as text, numbers or dates, tags keep them all as text
+fn keyvalue_tags(keyvalues: Option<BTreeMap<String, serde_json::Value>>) -> BTreeMap<String, String> {
+    keyvalues.into_iter().flatten()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(text) => text,
+                other => other.to_string(),
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+impl From<PinataPin> for PinRecord {
+    fn from(pin: PinataPin) -> Self {
+        let mut metadata = PinMetadata::default();
+        if let Some(pinata) = pin.metadata {
+            metadata.name = pinata.name;
+            metadata.tags = keyvalue_tags(pinata.keyvalues);
+        }
+        PinRecord { cid: pin.ipfs_pin_hash, size: pin.size, date: pin.date_pinned, status: JobStatus::Pinned, metadata }
+    }
+}
+
+/// One page of `/pinning/pinJobs`, the pin by hash requests Pinata is still working on or
+/// gave up on
+#[derive(Deserialize, Debug)]
+struct PinataPinJobs {
+    count: usize,
+    rows: Vec<PinataPinJob>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PinataPinJob {
+    ipfs_pin_hash: String,
+    date_queued: Option<String>,
+    name: Option<String>,
+    status: JobStatus,
+    keyvalues: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+impl From<PinataPinJob> for PinRecord {
+    fn from(job: PinataPinJob) -> Self {
+        let metadata = PinMetadata { name: job.name, tags: keyvalue_tags(job.keyvalues) };
+        PinRecord { cid: job.ipfs_pin_hash, size: None, date: job.date_queued, status: job.status, metadata }
     }
 }
 
@@ -26,19 +189,47 @@ impl PinataApiError {
 pub struct PinataProvider {
     pub name: String,
     pub api_url: String,
+    pub retry: RetryPolicy,
+    /// Whether the provider authenticates with a JWT, which the v3 upload api requires
+    uses_jwt: bool,
     client: Client
 }
 
 impl PinataProvider {
-    pub fn new(api_key: Option<String>, secret_api_key: Option<String>) -> Result<PinataProvider, Error> {
-        let api_key = if let Some(api_key) = api_key { api_key } else { std::env::var("PINATA_API_KEY").expect("PINATA_API_KEY env required to run test") };
-        let secret_api_key = if let Some(secret_api_key) = secret_api_key { secret_api_key } else { std::env::var("PINATA_SECRET_API_KEY").expect("SECRET_API_KEY env required to run test") };
-        // let secret_api_key = std::env::var("PINATA_SECRET_API_KEY").expect("SECRET_API_KEY env required to run test");
+    pub fn new(api_key: Option<String>, secret_api_key: Option<String>) -> Result<PinataProvider, ApiError> {
+        PinataProvider::with_resolver(api_key, secret_api_key, &CredentialResolver::load("pinata")?)
+    }
+
+    /// Creates a provider authenticating with a Pinata JWT instead of an api key pair
+    pub fn with_jwt(jwt: String) -> Result<PinataProvider, ApiError> {
+        let resolver = CredentialResolver::new("pinata", Default::default());
+        PinataProvider::with_headers(PinataProvider::jwt_headers(&resolver, &Secret::new(jwt))?, true)
+    }
+
+    /// Creates the provider with credentials looked up by `resolver` when they are not passed.
+    /// Without an explicit key pair a configured JWT is preferred over the api keys
+    pub fn with_resolver(api_key: Option<String>, secret_api_key: Option<String>, resolver: &CredentialResolver) -> Result<PinataProvider, ApiError> {
+        if api_key.is_none() && secret_api_key.is_none() {
+            if let Some(jwt) = resolver.find(None, "jwt", "PINATA_JWT")? {
+                return PinataProvider::with_headers(PinataProvider::jwt_headers(resolver, &jwt)?, true);
+            }
+        }
+        let api_key = resolver.resolve(api_key, "api_key", "PINATA_API_KEY")?;
+        let secret_api_key = resolver.resolve(secret_api_key, "secret_api_key", "PINATA_SECRET_API_KEY")?;
 
         let mut  default_headers = HeaderMap::new();
-        default_headers.insert("pinata_api_key", api_key.parse().unwrap());
-        default_headers.insert("pinata_secret_api_key", secret_api_key.parse().unwrap());
+        default_headers.insert("pinata_api_key", resolver.header("api_key", api_key.expose())?);
+        default_headers.insert("pinata_secret_api_key", resolver.header("secret_api_key", secret_api_key.expose())?);
+        PinataProvider::with_headers(default_headers, false)
+    }
+
+    fn jwt_headers(resolver: &CredentialResolver, jwt: &Secret) -> Result<HeaderMap, ApiError> {
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert(AUTHORIZATION, resolver.header("jwt", &format!("Bearer {}", jwt.expose()))?);
+        Ok(default_headers)
+    }
 
+    fn with_headers(default_headers: HeaderMap, uses_jwt: bool) -> Result<PinataProvider, ApiError> {
         let client = ClientBuilder::new()
             .default_headers(default_headers)
             .build()?;
@@ -46,31 +237,114 @@ impl PinataProvider {
         Ok(PinataProvider {
             name: "Pinata Provider".to_string(),
             api_url: "https://api.pinata.cloud".to_string(),
+            retry: RetryPolicy::default(),
+            uses_jwt,
             client
         })
     }
 
-    async fn parse_result<R>(&self, response: Response) -> Result<R, ApiError>
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn parse_result<R>(&self, response: Response, scope: &str) -> Result<R, ApiError>
       where R: DeserializeOwned
     {
         if response.status().is_success() {
             let result = response.json::<R>().await?;
             Ok(result)
         } else {
-            let error = response.json::<PinataApiError>().await?;
-            println!("Error {:?}", error);
-            Err(ApiError::GenericError(error.message()))
+            Err(self.parse_error(response, scope).await)
         }
     }
 
-    async fn parse_ok_result(&self, response: Response) -> Result<(), ApiError>
+    async fn parse_ok_result(&self, response: Response, scope: &str) -> Result<(), ApiError>
     {
         if response.status().is_success() {
             Ok(())
         } else {
-            let error = response.json::<PinataApiError>().await?;
-            println!("Error {:?}", error);
-            Err(ApiError::GenericError(error.message()))
+            Err(self.parse_error(response, scope).await)
+        }
+    }
+
+    /// Scoped keys are refused with a 403 when they lack the scope of the call
+    async fn parse_error(&self, response: Response, scope: &str) -> ApiError {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        let error = serde_json::from_str::<PinataApiError>(&body).ok();
+        let message = error.as_ref().map(|error| error.message()).unwrap_or(body);
+
+        if error.map(|error| error.is_missing_scope(scope)).unwrap_or(false) {
+            return ApiError::MissingScope { provider: "pinata".to_string(), scope: scope.to_string(), message };
+        }
+        ApiError::HttpStatus { status: status.as_u16(), message }
+    }
+
+    async fn list_pinned(&self, filter: &PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let mut query = vec![
+            ("status".to_string(), "pinned".to_string()),
+            ("pageLimit".to_string(), PIN_LIST_PAGE_LIMIT.to_string()),
+        ];
+        if let Some(name) = filter.name.as_ref() {
+            query.push(("metadata[name]".to_string(), name.clone()));
+        }
+        if !filter.tags.is_empty() {
+            let keyvalues = filter.tags.iter()
+                .map(|(key, value)| (key.clone(), serde_json::json!({ "value": value, "op": "eq" })))
+                .collect::<serde_json::Map<String, serde_json::Value>>();
+            query.push(("metadata[keyvalues]".to_string(), serde_json::Value::Object(keyvalues).to_string()));
+        }
+        if let Some(after) = filter.after.as_deref() {
+            query.push(("pinStart".to_string(), utils::start_of_day(after)));
+        }
+        if let Some(before) = filter.before.as_deref() {
+            query.push(("pinEnd".to_string(), utils::start_of_day(before)));
+        }
+
+        let mut records = vec![];
+        let mut offset = 0;
+        loop {
+            let response = self.retry.send(&self.name, None, || {
+                Ok(self.client.get(format!("{}{}", &self.api_url, "/data/pinList"))
+                    .query(&query)
+                    .query(&[("pageOffset", offset)]))
+            }).await?;
+
+            let page = self.parse_result::<PinataPinList>(response, SCOPE_PIN_LIST).await?;
+            let fetched = page.rows.len();
+            offset += fetched;
+            records.extend(page.rows.into_iter().map(PinRecord::from).filter(|record| filter.matches(record)));
+            if fetched == 0 || offset >= page.count {
+                return Ok(records);
+            }
+        }
+    }
+
+    /// Pin jobs can only be narrowed by status on Pinata's side, the rest of the filter is
+    /// checked here
+    async fn list_pin_jobs(&self, filter: &PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let mut query = vec![("limit".to_string(), PIN_LIST_PAGE_LIMIT.to_string())];
+        if let Some(status) = filter.status.as_ref() {
+            query.push(("status".to_string(), serde_json::json!(status).as_str().unwrap_or_default().to_string()));
+        }
+
+        let mut records = vec![];
+        let mut offset = 0;
+        loop {
+            let response = self.retry.send(&self.name, None, || {
+                Ok(self.client.get(format!("{}{}", &self.api_url, "/pinning/pinJobs"))
+                    .query(&query)
+                    .query(&[("offset", offset)]))
+            }).await?;
+
+            let page = self.parse_result::<PinataPinJobs>(response, SCOPE_PIN_JOBS).await?;
+            let fetched = page.rows.len();
+            offset += fetched;
+            records.extend(page.rows.into_iter().map(PinRecord::from).filter(|record| filter.matches(record)));
+            if fetched == 0 || offset >= page.count {
+                return Ok(records);
+            }
         }
     }
 }
@@ -81,22 +355,15 @@ impl StorageProvider for PinataProvider {
         "Pinata Provider".to_string()
     }
 
-    fn init(&self) -> bool {
-        println!("Initializing Pinata");
-        let mut token: String = "".to_string();
-        println!("Enter your Pinata jwt key");
+    async fn init(&self) -> Result<(), ApiError> {
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}{}", &self.api_url, "/data/testAuthentication")))
+        }).await?;
 
-        // todo: make this optional to skip uploading to pinata
-        io::stdin()
-            .read_line(&mut token)
-            .expect("Please enter your jwt api key");
-
-        // todo: read details from env
-        let token = utils::trim_newline(&mut token);
-        if token == String::from("") {
-            return false;
-        };
-        true
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(utils::rejected_credential("pinata", "credentials"));
+        }
+        self.parse_ok_result(response, SCOPE_TEST_AUTHENTICATION).await
     }
 
     fn api_url(&self) -> String {
@@ -104,51 +371,110 @@ impl StorageProvider for PinataProvider {
     }
 
     async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
-        let form = transform_file_to_form(&pin_data)?; // Form::new();
+        let response = self.retry.send(&self.name, pin_data.progress.as_ref(), || {
+            let mut form = transform_file_to_form(&pin_data)?;
+            if !pin_data.metadata.is_empty() {
+                form = form.text("pinataMetadata", serde_json::json!(PinataMetadata::from(&pin_data.metadata)).to_string());
+            }
+            if let Some(options) = pinata_options(&pin_data.options) {
+                form = form.text("pinataOptions", options.to_string());
+            }
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/pinning/pinFileToIPFS")).multipart(form))
+        }).await?;
 
-        let response = self.client.post(format!("{}{}", &self.api_url, "/pinning/pinFileToIPFS"))
-            .multipart(form)
-            .send()
-            .await?;
-
-        self.parse_result(response).await
+        self.parse_result(response, SCOPE_PIN_FILE).await
         // Ok(PinnedObject { ipfs_hash: "".to_string(), pin_size: 5583924, timestamp: "9864773747".to_string() })
     }
 
     #[allow(unused_variables)]
     async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
         let file = fs::read_to_string(pin_data.file)?;
-        let data:serde_json::Value = serde_json::from_str(file.as_str()).expect("Could not parse json file");
-        let response = self.client.post(format!("{}{}", &self.api_url, "/pinning/pinJSONToIPFS"))
-            .json(&data)
-            .send()
-            .await?;
+        let mut data:serde_json::Value = serde_json::from_str(file.as_str()).expect("Could not parse json file");
+        let options = pinata_options(&pin_data.options);
+        if !pin_data.metadata.is_empty() || options.is_some() {
+            data = serde_json::json!({ "pinataContent": data });
+            if !pin_data.metadata.is_empty() {
+                data["pinataMetadata"] = serde_json::json!(PinataMetadata::from(&pin_data.metadata));
+            }
+            if let Some(options) = options {
+                data["pinataOptions"] = options;
+            }
+        }
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/pinning/pinJSONToIPFS")).json(&data))
+        }).await?;
 
-        self.parse_result(response).await
+        self.parse_result(response, SCOPE_PIN_JSON).await
     }
 
- #[allow(unused_variables)]
-    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
-        let response = self.client.post(format!("{}{}", &self.api_url, "/pinning/pinByHash"))
-            .json(&pin_data)
-            .send()
-            .await?;
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        if !self.uses_jwt {
+            return Err(ApiError::GenericError("pin_car needs a Pinata JWT, the v3 upload api does not accept api keys: set PINATA_JWT or jwt in patter.toml".to_string()));
+        }
+        let file_name = Path::new(&pin_data.file).file_name().map(|name| name.to_string_lossy().to_string());
+        let response = self.retry.send(&self.name, None, || {
+            let part = file_part(Path::new(&pin_data.file), None)?
+                .file_name(file_name.clone().unwrap_or_else(|| "upload.car".to_string()))
+                .mime_str("application/vnd.ipld.car")?;
+            let form = Form::new()
+                .part("file", part)
+                .text("car", "true");
+            Ok(self.client.post(format!("{}{}", PINATA_UPLOADS_URL, "/v3/files")).multipart(form))
+        }).await?;
 
-        self.parse_result(response).await
+        let res = self.parse_result::<PinataUpload>(response, SCOPE_FILES_WRITE).await?;
+        println!("[PinataProvider::PinCar] {:?}", res);
+        Ok(PinnedObject { ipfs_hash: res.data.cid, pin_size: res.data.size, timestamp: res.data.created_at, verified: None })
     }
 
     #[allow(unused_variables)]
-    async fn pin_directory(&self) -> Result<(), ApiError> {
-        todo!()
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        let mut body = serde_json::json!({ "hashToPin": pin_data.hash_to_pin });
+        if !pin_data.metadata.is_empty() {
+            body["pinataMetadata"] = serde_json::json!(PinataMetadata::from(&pin_data.metadata));
+        }
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.post(format!("{}{}", &self.api_url, "/pinning/pinByHash")).json(&body))
+        }).await?;
+
+        self.parse_result(response, SCOPE_PIN_BY_HASH).await
+    }
+
+    /// Directories go through the same multipart upload as files
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.pin_file(pin_data).await
     }
 
     #[allow(unused_variables)]
     async fn unpin(&self, param: UnPin) -> Result<(), ApiError> {
-        let response = self.client.delete(format!("{}{}{}", &self.api_url, "/pinning/unpin/", &param.cid))
-            .send()
-            .await?;
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.delete(format!("{}{}{}", &self.api_url, "/pinning/unpin/", &param.cid)))
+        }).await?;
 
-        self.parse_ok_result(response).await
+        self.parse_ok_result(response, SCOPE_UNPIN).await
+    }
+
+    /// Pinned content comes from `/data/pinList`, pins Pinata has not finished from
+    /// `/pinning/pinJobs`, without a status filter both are listed
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let (pinned, jobs) = match &filter.status {
+            None => (true, true),
+            Some(JobStatus::Pinned) => (true, false),
+            Some(status @ (JobStatus::Queued | JobStatus::Pinning | JobStatus::Failed)) => {
+                println!("[{}] Pinata has no {:?} pins, its pin jobs are prechecking, searching, retrieving, expired, over_free_limit, over_max_size, invalid_object or bad_host_node", self.name, status);
+                (false, false)
+            }
+            Some(_) => (false, true),
+        };
+
+        let mut records = vec![];
+        if pinned {
+            records.extend(self.list_pinned(&filter).await?);
+        }
+        if jobs {
+            records.extend(self.list_pin_jobs(&filter).await?);
+        }
+        Ok(records)
     }
 }
 
@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use reqwest::{Client, ClientBuilder, Response, StatusCode};
+use reqwest::header::HeaderMap;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use crate::api::data::{JobStatus, PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinMetadata, PinRecord, PinnedObject, UnPin};
+use crate::data::StorageProvider;
+use crate::credentials::CredentialResolver;
+use crate::errors::ApiError;
+use crate::retry::RetryPolicy;
+use crate::utils::{format_timestamp, parse_timestamp, rejected_credential, start_of_day};
+
+/// Error body defined by the IPFS Pinning Services API
+#[derive(Debug, Deserialize)]
+pub(crate) struct PinningServiceApiError {
+    error: PinningServiceErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinningServiceErrorDetails {
+    reason: String,
+    details: Option<String>,
+}
+
+impl PinningServiceApiError {
+    pub fn message(&self) -> String {
+        match &self.error.details {
+            Some(details) => format!("{}: {}", self.error.reason, details),
+            None => self.error.reason.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Pin {
+    cid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The spec's free form metadata, tags are stored here
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    meta: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinDetails {
+    cid: String,
+    name: Option<String>,
+    meta: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinStatus {
+    requestid: String,
+    status: JobStatus,
+    created: Option<String>,
+    pin: PinDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct PinResults {
+    #[serde(default)]
+    count: usize,
+    results: Vec<PinStatus>,
+}
+
+/// Most pins the spec lets `GET /pins` return in one page
+const PIN_LIST_LIMIT: usize = 1000;
+
+/// Every status a pin request can have, the spec only lists pinned requests unless asked
+const ALL_STATUSES: &str = "queued,pinning,pinned,failed";
+
+/// Provider for any service implementing the vendor neutral IPFS Pinning Services API,
+/// such as Filebase, 4EVERLAND or a self-hosted pinning service
+#[derive(Debug)]
+pub struct PinningServiceProvider {
+    pub name: String,
+    pub api_url: String,
+    /// Time between two status checks of a queued pin
+    pub poll_interval: Duration,
+    /// How long `pin_by_hash` waits for a pin to leave the queue before returning its status
+    pub poll_timeout: Duration,
+    pub retry: RetryPolicy,
+    client: Client
+}
+
+impl PinningServiceProvider {
+    pub fn new(endpoint: Option<String>, token: Option<String>) -> Result<PinningServiceProvider, ApiError> {
+        PinningServiceProvider::with_resolver(endpoint, token, &CredentialResolver::load("pinning_service")?)
+    }
+
+    /// Creates the provider with a token looked up by `resolver` when it is not passed
+    pub fn with_resolver(endpoint: Option<String>, token: Option<String>, resolver: &CredentialResolver) -> Result<PinningServiceProvider, ApiError> {
+        let endpoint = match endpoint.or_else(|| std::env::var("PINNING_SERVICE_ENDPOINT").ok()) {
+            Some(endpoint) => endpoint,
+            None => return Err(ApiError::MissingConfig { provider: "pinning_service".to_string(), field: "endpoint".to_string(), env: "PINNING_SERVICE_ENDPOINT".to_string() }),
+        };
+        let token = resolver.resolve(token, "token", "PINNING_SERVICE_TOKEN")?;
+
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert("Authorization", resolver.header("token", &format!("Bearer {}", token.expose()))?);
+
+        let client = ClientBuilder::new()
+            .default_headers(default_headers)
+            .build()?;
+
+        Ok(PinningServiceProvider {
+            name: "Pinning Service Provider".to_string(),
+            api_url: endpoint.trim_end_matches('/').to_string(),
+            poll_interval: Duration::from_secs(2),
+            poll_timeout: Duration::from_secs(30),
+            retry: RetryPolicy::default(),
+            client
+        })
+    }
+
+    pub fn with_polling(mut self, poll_interval: Duration, poll_timeout: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Fetches the current status of a pin request
+    pub async fn pin_status(&self, request_id: &str) -> Result<PinByHashResult, ApiError> {
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}/pins/{}", &self.api_url, request_id)))
+        }).await?;
+
+        let status = self.parse_result::<PinStatus>(response).await?;
+        Ok(status.into())
+    }
+
+    async fn parse_result<R>(&self, response: Response) -> Result<R, ApiError>
+        where R: DeserializeOwned
+    {
+        if response.status().is_success() {
+            let result = response.json::<R>().await?;
+            Ok(result)
+        } else {
+            let error = response.json::<PinningServiceApiError>().await?;
+            println!("Error {:?}", error);
+            Err(ApiError::GenericError(error.message()))
+        }
+    }
+
+    async fn parse_ok_result(&self, response: Response) -> Result<(), ApiError> {
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            let error = response.json::<PinningServiceApiError>().await?;
+            println!("Error {:?}", error);
+            Err(ApiError::GenericError(error.message()))
+        }
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T, ApiError> {
+        Err(ApiError::GenericError(format!("{} is not part of the Pinning Services API", operation)))
+    }
+}
+
+impl From<PinStatus> for PinRecord {
+    fn from(status: PinStatus) -> Self {
+        let metadata = PinMetadata { name: status.pin.name, tags: status.pin.meta.unwrap_or_default() };
+        PinRecord { cid: status.pin.cid, size: None, date: status.created, status: status.status, metadata }
+    }
+}
+
+impl From<PinStatus> for PinByHashResult {
+    fn from(status: PinStatus) -> Self {
+        PinByHashResult { id: status.requestid, ipfs_hash: status.pin.cid, status: status.status, name: status.pin.name }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for PinningServiceProvider {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn init(&self) -> Result<(), ApiError> {
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}{}", &self.api_url, "/pins?limit=1")))
+        }).await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(rejected_credential("pinning_service", "token"));
+        }
+        self.parse_result::<serde_json::Value>(response).await.map(|_| ())
+    }
+
+    fn api_url(&self) -> String {
+        self.api_url.clone()
+    }
+
+    async fn pin_file(&self, _pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.unsupported("Pin file")
+    }
+
+    async fn pin_json(&self, _pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
+        self.unsupported("Pin json")
+    }
+
+    async fn pin_car(&self, _pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        self.unsupported("Pin car")
+    }
+
+    /// Requests the pin then polls its status until it is pinned, failed or `poll_timeout` runs out
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        let pin = Pin { cid: pin_data.hash_to_pin.to_string(), name: pin_data.metadata.name.clone(), meta: pin_data.metadata.tags.clone() };
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.post(format!("{}/pins", &self.api_url)).json(&pin))
+        }).await?;
+
+        let mut result: PinByHashResult = self.parse_result::<PinStatus>(response).await?.into();
+        let started = Instant::now();
+        while matches!(result.status, JobStatus::Queued | JobStatus::Pinning) && started.elapsed() < self.poll_timeout {
+            tokio::time::sleep(self.poll_interval).await;
+            result = self.pin_status(&result.id).await?;
+        }
+
+        if let JobStatus::Failed = result.status {
+            return Err(ApiError::GenericError(format!("Pinning service failed to pin {}", result.ipfs_hash)));
+        }
+        Ok(result)
+    }
+
+    async fn pin_directory(&self, _pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.unsupported("Pin directory")
+    }
+
+    /// Removes every pin request the service holds for the cid, whatever its status
+    async fn unpin(&self, data: UnPin) -> Result<(), ApiError> {
+        let cid = data.cid.to_string();
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}/pins", &self.api_url)).query(&[("cid", cid.as_str()), ("status", ALL_STATUSES)]))
+        }).await?;
+
+        let pins = self.parse_result::<PinResults>(response).await?;
+        if pins.results.is_empty() {
+            return Err(ApiError::GenericError(format!("No pin found for CID: {}", &data.cid)));
+        }
+        for pin in pins.results {
+            let response = self.retry.send(&self.name, None, || {
+                Ok(self.client.delete(format!("{}/pins/{}", &self.api_url, pin.requestid)))
+            }).await?;
+            self.parse_ok_result(response).await?;
+        }
+        Ok(())
+    }
+
+    /// Pages through `GET /pins` from the newest pin back, the spec filters on everything the
+    /// filter holds. The spec only lists pinned content unless asked for other statuses
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let status = match filter.status.as_ref() {
+            Some(status) => serde_json::json!(status).as_str().unwrap_or_default().to_string(),
+            None => ALL_STATUSES.to_string(),
+        };
+        let mut query = vec![("status".to_string(), status), ("limit".to_string(), PIN_LIST_LIMIT.to_string())];
+        if let Some(name) = filter.name.as_ref() {
+            query.push(("name".to_string(), name.clone()));
+            query.push(("match".to_string(), "ipartial".to_string()));
+        }
+        if !filter.tags.is_empty() {
+            query.push(("meta".to_string(), serde_json::json!(filter.tags).to_string()));
+        }
+        if let Some(after) = filter.after.as_deref() {
+            query.push(("after".to_string(), start_of_day(after)));
+        }
+
+        let mut before = filter.before.as_deref().map(start_of_day);
+        let mut seen = HashSet::new();
+        let mut records = vec![];
+        loop {
+            let response = self.retry.send(&self.name, None, || {
+                let mut request = self.client.get(format!("{}/pins", &self.api_url)).query(&query);
+                if let Some(before) = before.as_ref() {
+                    request = request.query(&[("before", before)]);
+                }
+                Ok(request)
+            }).await?;
+
+            let pins = self.parse_result::<PinResults>(response).await?;
+            let fetched = pins.results.len();
+            let oldest = pins.results.last().and_then(|pin| pin.created.as_deref()).and_then(parse_timestamp);
+            let mut fresh = 0;
+            for pin in pins.results {
+                if seen.insert(pin.requestid.clone()) {
+                    fresh += 1;
+                    let record = PinRecord::from(pin);
+                    if filter.matches(&record) {
+                        records.push(record);
+                    }
+                }
+            }
+            // the next page takes in the oldest instant of this one again, since more pins than
+            // fit in a page can share it, and is done once a page brings nothing new
+            match oldest {
+                Some(oldest) if fetched == PIN_LIST_LIMIT && fetched < pins.count && fresh > 0 => before = Some(format_timestamp(oldest + 1)),
+                _ => return Ok(records),
+            }
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, ClientBuilder, Method, Response, Url};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::api::data::{JobStatus, PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinMetadata, PinRecord, PinnedObject, UnPin};
+use crate::car::{create_car_file, read_root};
+use crate::data::StorageProvider;
+use crate::credentials::CredentialResolver;
+use crate::errors::ApiError;
+use crate::progress::ProgressReporter;
+use crate::retry::RetryPolicy;
+use crate::unixfs::{DagBuilder, DagOptions};
+use crate::utils::{create_temp_dir, rejected_credential, utc_parts};
+
+/// Size of each part of a multipart upload, objects up to this size are sent in one request
+pub const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+/// Smallest part size S3 accepts for every part but the last
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// Object metadata header the IPFS backed S3 services return the cid in
+const CID_HEADER: &str = "x-amz-meta-cid";
+/// Object keeping the key, name and tags of every pin by cid, so finding or listing pins
+/// needs neither a HEAD request per object nor a walk through the whole bucket. It is only a
+/// cache: what it says is checked against the object metadata, and pins missing from it are
+/// looked up there. On IPFS backed buckets it is stored and pinned like any other object, it
+/// is left out of listings
+const INDEX_KEY: &str = ".patter/index.json";
+/// Times an index update is tried again after another client changed the index under it
+const INDEX_WRITE_ATTEMPTS: u32 = 5;
+
+/// Where the index finds a pin and what it was stored with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct IndexEntry {
+    key: String,
+    #[serde(default)]
+    metadata: PinMetadata,
+}
+
+type PinIndex = BTreeMap<String, IndexEntry>;
+
+/// Access keys used to sign requests with AWS signature version 4
+#[derive(Clone)]
+pub(crate) struct SigV4 {
+    pub access_key: String,
+    pub secret_key: String,
+    pub region: String,
+}
+
+impl fmt::Debug for SigV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigV4")
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<redacted>")
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl SigV4 {
+    /// Builds the `Authorization` header for a request. `headers` are all the signed headers,
+    /// including `host`, `x-amz-date` and `x-amz-content-sha256`
+    pub fn authorization(&self, method: &str, uri: &str, query: &str, headers: &[(String, String)], payload_hash: &str, amz_date: &str) -> String {
+        let mut headers = headers.iter()
+            .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+            .collect::<Vec<(String, String)>>();
+        headers.sort();
+
+        let canonical_headers = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect::<String>();
+        let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>().join(";");
+        let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, uri, query, canonical_headers, signed_headers, payload_hash);
+
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+
+        let key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        let key = hmac(&key, self.region.as_bytes());
+        let key = hmac(&key, b"s3");
+        let key = hmac(&key, b"aws4_request");
+        let signature = HEXLOWER.encode(&hmac(&key, string_to_sign.as_bytes()));
+
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope, signed_headers, signature)
+    }
+}
+
+/// Provider for S3 compatible object storage backed by IPFS, such as Filebase, which reports
+/// the cid of every object in its metadata
+#[derive(Debug)]
+pub struct S3CompatibleProvider {
+    pub name: String,
+    pub api_url: String,
+    pub bucket: String,
+    /// Objects larger than this are sent with a multipart upload in parts of this size
+    pub part_size: u64,
+    pub retry: RetryPolicy,
+    signer: SigV4,
+    client: Client
+}
+
+impl S3CompatibleProvider {
+    pub fn new(endpoint: Option<String>, bucket: Option<String>, access_key: Option<String>, secret_key: Option<String>) -> Result<S3CompatibleProvider, ApiError> {
+        S3CompatibleProvider::with_resolver(endpoint, bucket, access_key, secret_key, &CredentialResolver::load("s3")?)
+    }
+
+    /// Creates the provider with access keys looked up by `resolver` when they are not passed
+    pub fn with_resolver(endpoint: Option<String>, bucket: Option<String>, access_key: Option<String>, secret_key: Option<String>, resolver: &CredentialResolver) -> Result<S3CompatibleProvider, ApiError> {
+        let endpoint = required(endpoint, "endpoint", "S3_ENDPOINT")?;
+        let bucket = required(bucket, "bucket", "S3_BUCKET")?;
+        let access_key = resolver.resolve(access_key, "access_key_id", "S3_ACCESS_KEY_ID")?.expose().to_string();
+        let secret_key = resolver.resolve(secret_key, "secret_access_key", "S3_SECRET_ACCESS_KEY")?.expose().to_string();
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        Url::parse(&endpoint).map_err(|e| ApiError::GenericError(format!("Invalid S3 endpoint {}: {}", endpoint, e)))?;
+
+        let client = ClientBuilder::new().build()?;
+
+        Ok(S3CompatibleProvider {
+            name: "S3 Compatible Provider".to_string(),
+            api_url: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            part_size: DEFAULT_PART_SIZE,
+            retry: RetryPolicy::default(),
+            signer: SigV4 { access_key, secret_key, region },
+            client
+        })
+    }
+
+    pub fn with_region<S: Into<String>>(mut self, region: S) -> Self {
+        self.signer.region = region.into();
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the multipart part size, raised to the 5MiB S3 minimum when smaller
+    pub fn with_part_size(mut self, part_size: u64) -> Self {
+        self.part_size = part_size.max(MIN_PART_SIZE);
+        self
+    }
+
+    /// Signs and sends a request for `key` in the bucket, or for the bucket itself when `key` is
+    /// `None`. Error responses are turned into an `ApiError` with the S3 error code and message
+    async fn send(&self, method: Method, key: Option<&str>, query: &[(&str, &str)], headers: &[(&str, &str)], body: Vec<u8>) -> Result<Response, ApiError> {
+        let response = self.send_raw(method, key, query, headers, body).await?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            let status = response.status();
+            let body = response.text().await?;
+            let message = xml_values(&body, "Message").into_iter().next().unwrap_or_else(|| status.to_string());
+            let code = xml_values(&body, "Code").into_iter().next().unwrap_or_default();
+            Err(ApiError::GenericError(format!("{} {}", code, message).trim().to_string()))
+        }
+    }
+
+    /// Like `send`, but hands back error responses as they are
+    async fn send_raw(&self, method: Method, key: Option<&str>, query: &[(&str, &str)], headers: &[(&str, &str)], body: Vec<u8>) -> Result<Response, ApiError> {
+        let base = Url::parse(&self.api_url).map_err(|e| ApiError::GenericError(format!("{}", e)))?;
+        let host = match (base.host_str(), base.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(ApiError::GenericError(format!("No host in endpoint {}", self.api_url))),
+        };
+
+        let mut uri = format!("{}/{}", base.path().trim_end_matches('/'), uri_encode(&self.bucket, false));
+        if let Some(key) = key {
+            uri = format!("{}/{}", uri, uri_encode(key, true));
+        }
+        let mut query = query.iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+            .collect::<Vec<String>>();
+        query.sort();
+        let query = query.join("&");
+
+        let payload_hash = sha256_hex(&body);
+        let amz_date = amz_date(SystemTime::now());
+        let mut signed = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        signed.extend(headers.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+        let authorization = self.signer.authorization(method.as_str(), &uri, &query, &signed, &payload_hash, &amz_date);
+
+        let url = format!("{}://{}{}{}{}", base.scheme(), signed[0].1, uri, if query.is_empty() { "" } else { "?" }, query);
+        // the signature covers x-amz-date, so a retry after more than 15 minutes of backoff is refused
+        self.retry.send(&self.name, None, || {
+            let mut request = self.client.request(method.clone(), url.as_str())
+                .header("authorization", authorization.as_str())
+                .body(body.clone());
+            for (name, value) in signed.iter().skip(1) {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            Ok(request)
+        }).await
+    }
+
+    /// Uploads the file at `path` as `key`, in parts when it is larger than `part_size`
+    async fn upload(&self, path: &Path, key: &str, headers: &[(&str, &str)], progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError> {
+        let size = fs::metadata(path)?.len();
+        let cid = if size > self.part_size {
+            self.upload_multipart(path, key, size, headers, progress).await?
+        } else {
+            let response = self.send(Method::PUT, Some(key), &[], headers, fs::read(path)?).await?;
+            if let Some(progress) = progress {
+                progress.sent(&path.to_string_lossy(), size, size, size);
+            }
+            cid_header(&response)
+        };
+
+        // not every backend echoes the metadata on upload, it is always there on HEAD
+        let cid = match cid {
+            Some(cid) => cid,
+            None => {
+                let response = self.send(Method::HEAD, Some(key), &[], &[], vec![]).await?;
+                cid_header(&response).ok_or_else(|| ApiError::GenericError(format!("No cid in the metadata of {}", key)))?
+            }
+        };
+        Ok(PinnedObject { ipfs_hash: cid, pin_size: size, timestamp: "".to_string(), verified: None })
+    }
+
+    async fn upload_multipart(&self, path: &Path, key: &str, size: u64, headers: &[(&str, &str)], progress: Option<&ProgressReporter>) -> Result<Option<String>, ApiError> {
+        let response = self.send(Method::POST, Some(key), &[("uploads", "")], headers, vec![]).await?;
+        let upload_id = xml_values(&response.text().await?, "UploadId").into_iter().next()
+            .ok_or_else(|| ApiError::GenericError(format!("No upload id for {}", key)))?;
+
+        let result = self.upload_parts(path, key, size, &upload_id, progress).await;
+        if result.is_err() {
+            if let Err(e) = self.send(Method::DELETE, Some(key), &[("uploadId", &upload_id)], &[], vec![]).await {
+                println!("Could not abort multipart upload {}: {}", upload_id, e);
+            }
+        }
+        result
+    }
+
+    async fn upload_parts(&self, path: &Path, key: &str, size: u64, upload_id: &str, progress: Option<&ProgressReporter>) -> Result<Option<String>, ApiError> {
+        let mut file = File::open(path)?;
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        let parts = size.div_ceil(self.part_size);
+
+        for part_number in 1..=parts {
+            let offset = (part_number - 1) * self.part_size;
+            let part_size = self.part_size.min(size - offset);
+            let mut part = vec![0u8; part_size as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut part)?;
+
+            println!("[S3CompatibleProvider::Upload] part {}/{} of {}", part_number, parts, key);
+            let part_number = part_number.to_string();
+            let response = self.send(Method::PUT, Some(key), &[("partNumber", &part_number), ("uploadId", upload_id)], &[], part).await?;
+            let etag = response.headers().get("etag").and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| ApiError::GenericError(format!("No etag for part {} of {}", part_number, key)))?;
+            complete.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", part_number, etag));
+            if let Some(progress) = progress {
+                progress.sent(&path.to_string_lossy(), offset + part_size, size, part_size);
+            }
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        let response = self.send(Method::POST, Some(key), &[("uploadId", upload_id)], &[], complete.into_bytes()).await?;
+        Ok(cid_header(&response))
+    }
+
+    /// Uploads the dag of a directory as a car that the backend imports
+    async fn upload_as_car(&self, files: &[String], key: &str, metadata: &[(&str, &str)], progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError> {
+        let dir = create_temp_dir("s3-car")?;
+        let path = dir.join("upload.car");
+        let mut headers = vec![("x-amz-meta-import", "car")];
+        headers.extend_from_slice(metadata);
+        let result = match create_car_file(files, DagOptions::v1(), &path) {
+            Ok(_) => self.upload(&path, key, &headers, progress).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            println!("Could not remove car file in {:?}: {}", dir, e);
+        }
+        result
+    }
+
+    /// Reads the index and its etag, empty and without an etag when the bucket has none yet
+    async fn read_index(&self) -> Result<(PinIndex, Option<String>), ApiError> {
+        match self.send(Method::GET, Some(INDEX_KEY), &[], &[], vec![]).await {
+            Ok(response) => {
+                let etag = response.headers().get("etag").and_then(|etag| etag.to_str().ok()).map(|etag| etag.to_string());
+                let body = response.text().await?;
+                let index = serde_json::from_str(&body)
+                    .map_err(|e| ApiError::GenericError(format!("{} is not a pin index: {}", INDEX_KEY, e)))?;
+                Ok((index, etag))
+            }
+            Err(ApiError::GenericError(message)) if message.starts_with("NoSuchKey") => Ok((PinIndex::new(), None)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The index for lookups, empty when it cannot be read so every pin is looked up in the
+    /// object metadata instead
+    async fn cached_index(&self) -> PinIndex {
+        match self.read_index().await {
+            Ok((index, _)) => index,
+            Err(e) => {
+                println!("[s3] Looking pins up in the object metadata, the index is unusable: {}", e);
+                PinIndex::new()
+            }
+        }
+    }
+
+    /// Records the pin of `cid` in the index, or drops it when `entry` is `None`. The write only
+    /// goes through when nobody changed the index since it was read, and is tried again on top
+    /// of their change otherwise. A corrupt index is left for the user to look at. The object
+    /// is stored or deleted by then and the index is only a cache, so a failure is reported
+    /// without failing the pin
+    async fn update_index(&self, cid: &str, entry: Option<IndexEntry>) {
+        let mut attempt = 1;
+        let result = loop {
+            let (mut index, etag) = match self.read_index().await {
+                Ok(read) => read,
+                Err(e) => break Err(e),
+            };
+            match entry.clone() {
+                Some(entry) => {
+                    // an upload under the same key replaced the object of any earlier pin
+                    index.retain(|_, indexed| indexed.key != entry.key);
+                    index.insert(cid.to_string(), entry);
+                }
+                None => {
+                    index.remove(cid);
+                }
+            }
+            let condition = match etag.as_deref() {
+                Some(etag) => ("if-match", etag),
+                None => ("if-none-match", "*"),
+            };
+            let body = serde_json::json!(index).to_string().into_bytes();
+            let response = match self.send_raw(Method::PUT, Some(INDEX_KEY), &[], &[("content-type", "application/json"), condition], body).await {
+                Ok(response) => response,
+                Err(e) => break Err(e),
+            };
+            match response.status().as_u16() {
+                200..=299 => break Ok(()),
+                // 409 is what S3 answers when two conditional writes race
+                412 | 409 if attempt < INDEX_WRITE_ATTEMPTS => attempt += 1,
+                status => break Err(ApiError::HttpStatus { status, message: format!("Could not write {}", INDEX_KEY) }),
+            }
+        };
+        if let Err(e) = result {
+            println!("[s3] Could not update {}, the pin is looked up in its object metadata instead: {}", INDEX_KEY, e);
+        }
+    }
+
+    /// Key of the object holding `cid`. The index is checked against the object's metadata, and
+    /// when it does not know the cid the bucket is paged through, for objects stored before the
+    /// index was kept or whose index update was lost
+    async fn find_key(&self, cid: &str) -> Result<Option<String>, ApiError> {
+        if let Some(entry) = self.cached_index().await.remove(cid) {
+            let response = self.send_raw(Method::HEAD, Some(&entry.key), &[], &[], vec![]).await?;
+            if response.status().is_success() && cid_header(&response).as_deref() == Some(cid) {
+                return Ok(Some(entry.key));
+            }
+        }
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2")];
+            if let Some(token) = token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let response = self.send(Method::GET, None, &query, &[], vec![]).await?;
+            let body = response.text().await?;
+
+            for key in xml_values(&body, "Key").into_iter().filter(|key| key != INDEX_KEY) {
+                let response = self.send(Method::HEAD, Some(&key), &[], &[], vec![]).await?;
+                if cid_header(&response).as_deref() == Some(cid) {
+                    return Ok(Some(key));
+                }
+            }
+            token = xml_values(&body, "NextContinuationToken").into_iter().next();
+            if token.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for S3CompatibleProvider {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Checks the access keys may read the bucket. A HEAD response has no body to explain an
+    /// error, so the status is all there is to go by
+    async fn init(&self) -> Result<(), ApiError> {
+        let response = self.send_raw(Method::HEAD, None, &[], &[], vec![]).await?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            401 | 403 => Err(rejected_credential("s3", "access_key_id")),
+            status => Err(ApiError::HttpStatus { status, message: format!("Could not reach bucket {}", self.bucket) }),
+        }
+    }
+
+    fn api_url(&self) -> String {
+        self.api_url.clone()
+    }
+
+    async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        pin_data.options.warn_unsupported("s3", &[]);
+        let key = object_key(&pin_data.files)?;
+        let metadata = metadata_headers(&pin_data.metadata);
+        let metadata = metadata.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect::<Vec<(&str, &str)>>();
+        let pinned = match pin_data.files.as_slice() {
+            [file] if !Path::new(file).is_dir() => self.upload(Path::new(file), &key, &metadata, pin_data.progress.as_ref()).await?,
+            files => self.upload_as_car(files, &key, &metadata, pin_data.progress.as_ref()).await?,
+        };
+        self.update_index(&pinned.ipfs_hash, Some(IndexEntry { key, metadata: pin_data.metadata })).await;
+        Ok(pinned)
+    }
+
+    async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
+        let file = fs::read_to_string(&pin_data.file)?;
+        serde_json::from_str::<serde_json::Value>(file.as_str())
+            .map_err(|e| ApiError::GenericError(format!("Could not parse json file: {}", e)))?;
+
+        pin_data.options.warn_unsupported("s3", &[]);
+        let key = object_key(std::slice::from_ref(&pin_data.file))?;
+        let metadata = metadata_headers(&pin_data.metadata);
+        let mut headers = vec![("content-type", "application/json")];
+        headers.extend(metadata.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        let pinned = self.upload(Path::new(&pin_data.file), &key, &headers, None).await?;
+        self.update_index(&pinned.ipfs_hash, Some(IndexEntry { key, metadata: pin_data.metadata })).await;
+        Ok(pinned)
+    }
+
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        let key = format!("{}/{}", read_root(&pin_data.file)?, file_name(&pin_data.file)?);
+        let pinned = self.upload(Path::new(&pin_data.file), &key, &[("x-amz-meta-import", "car")], None).await?;
+        self.update_index(&pinned.ipfs_hash, Some(IndexEntry { key, metadata: PinMetadata::default() })).await;
+        Ok(pinned)
+    }
+
+    async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
+        Err(ApiError::GenericError(format!("Pin by Hash is not supported by S3 compatible storage, hash: {}", &pin_data.hash_to_pin)))
+    }
+
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.pin_file(pin_data).await
+    }
+
+    /// Deletes the object holding the cid, which unpins it on the backend
+    async fn unpin(&self, data: UnPin) -> Result<(), ApiError> {
+        match self.find_key(&data.cid.to_string()).await? {
+            Some(key) => {
+                self.send(Method::DELETE, Some(&key), &[], &[], vec![]).await?;
+                self.update_index(&data.cid.to_string(), None).await;
+                Ok(())
+            }
+            None => Err(ApiError::GenericError(format!("No object found for CID: {}", &data.cid))),
+        }
+    }
+
+    /// Pages through the bucket for the size and date of every object and takes the cid, name
+    /// and tags from the index. Objects missing from it, such as the ones stored before it was
+    /// kept, get a HEAD request for their metadata. Objects without a cid are left out
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let index = self.cached_index().await;
+        let indexed = index.iter()
+            .map(|(cid, entry)| (entry.key.as_str(), (cid, &entry.metadata)))
+            .collect::<HashMap<&str, (&String, &PinMetadata)>>();
+        let mut records = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2")];
+            if let Some(token) = token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let response = self.send(Method::GET, None, &query, &[], vec![]).await?;
+            let body = response.text().await?;
+
+            let objects = xml_values(&body, "Key").into_iter()
+                .zip(xml_values(&body, "Size"))
+                .zip(xml_values(&body, "LastModified"));
+            for ((key, size), date) in objects.filter(|((key, _), _)| key != INDEX_KEY) {
+                let (cid, metadata) = match indexed.get(key.as_str()) {
+                    Some((cid, metadata)) => (cid.to_string(), (*metadata).clone()),
+                    None => {
+                        let response = self.send(Method::HEAD, Some(&key), &[], &[], vec![]).await?;
+                        match cid_header(&response) {
+                            Some(cid) => (cid, header_metadata(&response)),
+                            None => continue,
+                        }
+                    }
+                };
+                let record = PinRecord { cid, size: size.parse().ok(), date: Some(date), status: JobStatus::Pinned, metadata };
+                if filter.matches(&record) {
+                    records.push(record);
+                }
+            }
+            token = xml_values(&body, "NextContinuationToken").into_iter().next();
+            if token.is_none() {
+                return Ok(records);
+            }
+        }
+    }
+}
+
+/// User metadata headers holding the name and tags of a pin, values are percent encoded
+/// since S3 only allows ascii in headers. Header names are folded to lower case, so the tags
+/// are kept together as one json object to come back with their keys as they were given
+fn metadata_headers(metadata: &PinMetadata) -> Vec<(String, String)> {
+    let mut headers = vec![];
+    if let Some(name) = metadata.name.as_deref() {
+        headers.push(("x-amz-meta-name".to_string(), uri_encode(name, false)));
+    }
+    if !metadata.tags.is_empty() {
+        headers.push(("x-amz-meta-tags".to_string(), uri_encode(&serde_json::json!(metadata.tags).to_string(), false)));
+    }
+    headers
+}
+
+/// Name and tags `metadata_headers` stored with an object. Objects stored before the tags
+/// were kept as json have a lower cased `x-amz-meta-tag-` header for each tag
+fn header_metadata(response: &Response) -> PinMetadata {
+    let mut metadata = PinMetadata::default();
+    for (name, value) in response.headers().iter() {
+        let value = match value.to_str() {
+            Ok(value) => uri_decode(value),
+            Err(_) => continue,
+        };
+        if name.as_str() == "x-amz-meta-name" {
+            metadata.name = Some(value);
+        } else if name.as_str() == "x-amz-meta-tags" {
+            match serde_json::from_str(&value) {
+                Ok(tags) => metadata.tags.extend::<BTreeMap<String, String>>(tags),
+                Err(e) => println!("[s3] Could not read the tags {}: {}", value, e),
+            }
+        } else if let Some(key) = name.as_str().strip_prefix("x-amz-meta-tag-") {
+            metadata.tags.insert(key.to_string(), value);
+        }
+    }
+    metadata
+}
+
+fn required(value: Option<String>, field: &str, env: &str) -> Result<String, ApiError> {
+    value.or_else(|| std::env::var(env).ok())
+        .ok_or_else(|| ApiError::MissingConfig { provider: "s3".to_string(), field: field.to_string(), env: env.to_string() })
+}
+
+/// Objects are keyed by the cid of what they hold and named after the file or directory,
+/// `<cid>/<name>`, so pins of different content never share a key even when their files are
+/// named alike. Several files are keyed by the cid of the directory wrapping them alone
+fn object_key(files: &[String]) -> Result<String, ApiError> {
+    let cid = DagBuilder::new(DagOptions::v1()).root_cid(files)?;
+    match files {
+        [file] => Ok(format!("{}/{}", cid, file_name(file)?)),
+        _ => Ok(cid.to_string()),
+    }
+}
+
+fn file_name(file: &str) -> Result<String, ApiError> {
+    let name = Path::new(file).canonicalize().ok()
+        .and_then(|path| path.file_name().map(|name| name.to_string_lossy().to_string()));
+    name.ok_or_else(|| ApiError::GenericError(format!("Could not get an object key for {}", file)))
+}
+
+fn cid_header(response: &Response) -> Option<String> {
+    response.headers().get(CID_HEADER).and_then(|cid| cid.to_str().ok()).map(|cid| cid.to_string())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    HEXLOWER.encode(&Sha256::digest(data))
+}
+
+/// Percent encodes everything but the RFC 3986 unreserved characters, and `/` when `keep_slash`
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Reverses `uri_encode`, leaving malformed escapes as they are
+fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' { value.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) } else { None };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Formats a time as the `YYYYMMDDTHHMMSSZ` timestamp SigV4 expects
+pub(crate) fn amz_date(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// Text of every `<tag>` element in an xml document, good enough for S3's flat responses
+fn xml_values(body: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = vec![];
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(rest[..end]
+                    .replace("&quot;", "\"")
+                    .replace("&apos;", "'")
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&amp;", "&"));
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
@@ -1,13 +1,25 @@
 use std::fs;
+use std::path::Path;
 use async_trait::async_trait;
-use reqwest::{Client, ClientBuilder, Response};
-use reqwest::header::{HeaderMap};
+use reqwest::{Client, ClientBuilder, RequestBuilder, Response, StatusCode};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, LINK};
 use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
-use crate::api::data::{PinByFile, PinByHash, PinByHashResult, PinByJson, PinnedObject, PinnedResult, UnPin};
+use crate::api::data::{JobStatus, PinByCar, PinByFile, PinByHash, PinByHashResult, PinByJson, PinFilter, PinMetadata, PinRecord, PinnedObject, PinnedResult, UnPin};
 use crate::data::StorageProvider;
-use crate::errors::{Error, ApiError};
-use crate::utils::transform_file_to_form;
+use crate::credentials::CredentialResolver;
+use crate::errors::ApiError;
+use crate::progress::ProgressReporter;
+use crate::retry::RetryPolicy;
+use crate::car::{stream_car_shards, CarShard};
+use crate::cid::CidVersion;
+use crate::unixfs::{DagBuilder, DagOptions};
+use crate::utils::{compare_timestamps, create_temp_dir, file_body, rejected_credential, start_of_day, total_file_size, transform_file_to_form};
+
+/// Largest body Web3Storage accepts in a single request
+pub const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+/// Most uploads `/user/uploads` returns in one page
+const UPLOADS_PAGE_SIZE: usize = 100;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Web3StorageApiError {
@@ -21,20 +33,65 @@ impl Web3StorageApiError {
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Web3StorageStatus {
+    cid: String,
+    dag_size: Option<u64>,
+    created: Option<String>,
+}
+
+/// One entry of `/user/uploads`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Web3StorageUpload {
+    cid: String,
+    name: Option<String>,
+    created: Option<String>,
+    dag_size: Option<u64>,
+    #[serde(default)]
+    pins: Vec<Web3StoragePin>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Web3StoragePin {
+    status: String,
+}
+
+impl From<Web3StorageUpload> for PinRecord {
+    /// An upload counts as pinned as soon as one of the nodes holding it has pinned it
+    fn from(upload: Web3StorageUpload) -> Self {
+        let status = if upload.pins.iter().any(|pin| pin.status == "Pinned") {
+            JobStatus::Pinned
+        } else if upload.pins.iter().any(|pin| pin.status == "Pinning") {
+            JobStatus::Pinning
+        } else {
+            JobStatus::Queued
+        };
+        PinRecord { cid: upload.cid, size: upload.dag_size, date: upload.created, status, metadata: PinMetadata { name: upload.name, ..PinMetadata::default() } }
+    }
+}
 
 #[derive(Debug)]
 pub struct Web3StorageProvider {
     pub name: String,
     pub api_url: String,
+    /// Uploads larger than this are split into car shards of at most this many bytes
+    pub shard_size: u64,
+    pub retry: RetryPolicy,
     client: Client
 }
 
 impl Web3StorageProvider {
-    pub fn new(token: Option<String>) -> Result<Web3StorageProvider, Error> {
-        // let api_token = std::env::var("WEB3STORAGE_API_TOKEN").expect("PINATA_API_KEY env required to run test");
-        let token = if let Some(token) = token { token } else { std::env::var("WEB3STORAGE_API_TOKEN").expect("WEB3STORAGE_API_TOKEN env required to run test") };
+    pub fn new(token: Option<String>) -> Result<Web3StorageProvider, ApiError> {
+        Web3StorageProvider::with_resolver(token, &CredentialResolver::load("web3")?)
+    }
+
+    /// Creates the provider with a token looked up by `resolver` when it is not passed
+    pub fn with_resolver(token: Option<String>, resolver: &CredentialResolver) -> Result<Web3StorageProvider, ApiError> {
+        let token = resolver.resolve(token, "token", "WEB3STORAGE_API_TOKEN")?;
         let mut  default_headers = HeaderMap::new();
-        default_headers.insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+        default_headers.insert("Authorization", resolver.header("token", &format!("Bearer {}", token.expose()))?);
 
         let client = ClientBuilder::new()
             .default_headers(default_headers)
@@ -43,10 +100,105 @@ impl Web3StorageProvider {
         Ok(Web3StorageProvider {
             name: "Web3Storage Provider".to_string(),
             api_url: "https://api.web3.storage".to_string(),
+            shard_size: MAX_UPLOAD_SIZE,
+            retry: RetryPolicy::default(),
             client
         })
     }
 
+    pub fn with_shard_size(mut self, shard_size: u64) -> Self {
+        self.shard_size = shard_size;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uploads `files` as car shards sharing one root, each shard as soon as it is written
+    async fn pin_file_sharded(&self, files: &[String], options: DagOptions, metadata: &PinMetadata, progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError> {
+        let dir = create_temp_dir("web3-shards")?;
+        let result = self.upload_shards(files, options, &dir, metadata, progress).await;
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            println!("Could not remove car shards in {:?}: {}", dir, e);
+        }
+        result
+    }
+
+    /// Every shard carries the root, so it is worked out first. The shards are then written on
+    /// a blocking thread that waits while the shard before is uploaded, so only a few shards are
+    /// on disk at any time
+    async fn upload_shards(&self, files: &[String], options: DagOptions, dir: &Path, metadata: &PinMetadata, progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError> {
+        let root = DagBuilder::new(options.clone()).root_cid(files)?;
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<CarShard>(1);
+        let writer = {
+            let (files, dir, root, shard_size) = (files.to_vec(), dir.to_path_buf(), root.clone(), self.shard_size);
+            tokio::task::spawn_blocking(move || stream_car_shards(&files, options, &dir, shard_size, &root, |shard| {
+                sender.blocking_send(shard).map_err(|_| ApiError::GenericError("The shard upload stopped".to_string()))
+            }))
+        };
+
+        let root = root.to_string();
+        let uploaded = self.upload_shard_stream(&mut receiver, &root, metadata, progress).await;
+        // stops the writer when an upload failed, and waits for it before its files are removed
+        drop(receiver);
+        let written = writer.await
+            .map_err(|e| ApiError::GenericError(format!("Writing the car shards failed: {}", e)))?;
+        let total_size = uploaded?;
+        written?;
+
+        let status = self.status(&root).await?;
+        println!("[Web3StorageProvider::PinFile] confirmed {:?}", status);
+        Ok(PinnedObject {
+            ipfs_hash: status.cid,
+            pin_size: status.dag_size.unwrap_or(total_size),
+            timestamp: status.created.unwrap_or_default(),
+            verified: None
+        })
+    }
+
+    /// Uploads the shards as they come and removes each once it is stored, returning the bytes sent
+    async fn upload_shard_stream(&self, shards: &mut tokio::sync::mpsc::Receiver<CarShard>, root: &str, metadata: &PinMetadata, progress: Option<&ProgressReporter>) -> Result<u64, ApiError> {
+        let mut total_size = 0;
+        let mut count = 0;
+        while let Some(shard) = shards.recv().await {
+            count += 1;
+            println!("[Web3StorageProvider::PinFile] uploading shard {} ({} bytes)", count, shard.size);
+            let pinned = self.post_car(&shard.path, metadata, progress).await?;
+            if pinned.ipfs_hash != root {
+                return Err(ApiError::GenericError(format!("Shard {} was stored under {}, expected root {}", count, pinned.ipfs_hash, root)));
+            }
+            total_size += shard.size;
+            if let Err(e) = fs::remove_file(&shard.path) {
+                println!("Could not remove car shard {:?}: {}", shard.path, e);
+            }
+        }
+        Ok(total_size)
+    }
+
+    async fn post_car(&self, path: &Path, metadata: &PinMetadata, progress: Option<&ProgressReporter>) -> Result<PinnedObject, ApiError> {
+        let size = fs::metadata(path)?.len();
+        let response = self.retry.send(&self.name, progress, || {
+            Ok(with_name(self.client.post(format!("{}{}", &self.api_url, "/car")), metadata)
+                .header(CONTENT_TYPE, "application/vnd.ipld.car")
+                .header(CONTENT_LENGTH, size)
+                .body(file_body(path, progress)?))
+        }).await?;
+
+        let res = self.parse_result::<PinnedResult>(response).await?;
+        println!("[Web3StorageProvider::PinCar] {:?}", res);
+        Ok(PinnedObject { ipfs_hash: res.cid, timestamp: "".to_string(), pin_size: 0, verified: None })
+    }
+
+    async fn status(&self, cid: &str) -> Result<Web3StorageStatus, ApiError> {
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}/status/{}", &self.api_url, cid)))
+        }).await?;
+
+        self.parse_result(response).await
+    }
+
     async fn parse_result<R>(&self, response: Response) -> Result<R, ApiError>
         where R: DeserializeOwned
     {
@@ -67,48 +219,129 @@ impl StorageProvider for Web3StorageProvider {
         "Web3Storage Provider".to_string()
     }
 
-    fn init(&self) -> bool {
-        todo!()
+    async fn init(&self) -> Result<(), ApiError> {
+        let response = self.retry.send(&self.name, None, || {
+            Ok(self.client.get(format!("{}{}", &self.api_url, "/user/uploads?size=1")))
+        }).await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(rejected_credential("web3", "token"));
+        }
+        self.parse_result::<serde_json::Value>(response).await.map(|_| ())
     }
+
     fn api_url(&self) -> String {
         "https://api.web3.storage".to_string()
     }
 
     async fn pin_file(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
-        let form = transform_file_to_form(&pin_data)?;
-
-        let response = self.client.post(format!("{}{}", &self.api_url, "/upload"))
-            .multipart(form)
-            .send()
-            .await?;
+        pin_data.metadata.warn_tags_dropped("web3");
+        // web3 only answers with v1 cids, so asking for v1 needs nothing sent
+        let supported: &[&str] = match pin_data.options.cid_version {
+            Some(CidVersion::V0) => &["wrap_with_directory"],
+            _ => &["cid_version", "wrap_with_directory"],
+        };
+        pin_data.options.warn_unsupported("web3", supported);
+        // the upload endpoint decides about wrapping itself, so wrapped uploads go as a car built here
+        if pin_data.options.wrap_with_directory || total_file_size(&pin_data.files)? > self.shard_size {
+            let options = DagOptions { wrap_with_directory: pin_data.options.wrap_with_directory, ..DagOptions::v1() };
+            return self.pin_file_sharded(&pin_data.files, options, &pin_data.metadata, pin_data.progress.as_ref()).await;
+        }
+        let response = self.retry.send(&self.name, pin_data.progress.as_ref(), || {
+            let form = transform_file_to_form(&pin_data)?;
+            Ok(with_name(self.client.post(format!("{}{}", &self.api_url, "/upload")), &pin_data.metadata).multipart(form))
+        }).await?;
 
         let res = self.parse_result::<PinnedResult>(response).await?;
         println!("[Web3StorageProvider::PinFile] {:?}", res);
-        Ok(PinnedObject { ipfs_hash: res.cid, timestamp: "".to_string(), pin_size: 0 })
+        Ok(PinnedObject { ipfs_hash: res.cid, timestamp: "".to_string(), pin_size: 0, verified: None })
     }
 
     async fn pin_json(&self, pin_data: PinByJson) -> Result<PinnedObject, ApiError> {
         let file = fs::read_to_string(pin_data.file)?;
         let data:serde_json::Value = serde_json::from_str(file.as_str()).expect("Could not parse json file");
-        let response = self.client.post(format!("{}{}", &self.api_url, "/upload"))
-            .json(&data)
-            .send()
-            .await?;
+        pin_data.metadata.warn_tags_dropped("web3");
+        let supported: &[&str] = match pin_data.options.cid_version {
+            Some(CidVersion::V0) => &[],
+            _ => &["cid_version"],
+        };
+        pin_data.options.warn_unsupported("web3", supported);
+        let response = self.retry.send(&self.name, None, || {
+            Ok(with_name(self.client.post(format!("{}{}", &self.api_url, "/upload")), &pin_data.metadata).json(&data))
+        }).await?;
 
         let res = self.parse_result::<PinnedResult>(response).await?;
         println!("[Web3StorageProvider::PinJson] {:?}", res);
-        Ok(PinnedObject { ipfs_hash: res.cid, timestamp: "".to_string(), pin_size: 0 })
+        Ok(PinnedObject { ipfs_hash: res.cid, timestamp: "".to_string(), pin_size: 0, verified: None })
+    }
+
+    async fn pin_car(&self, pin_data: PinByCar) -> Result<PinnedObject, ApiError> {
+        self.post_car(Path::new(&pin_data.file), &PinMetadata::default(), None).await
     }
 
     async fn pin_by_hash(&self, pin_data: PinByHash) -> Result<PinByHashResult, ApiError> {
         Err(ApiError::GenericError(format!("Pin by Hash not Implemented for Web3Storage, hash: {}", &pin_data.hash_to_pin)))
     }
 
-    async fn pin_directory(&self) -> Result<(), ApiError> {
-        todo!()
+    /// Directories go through the same multipart upload as files
+    async fn pin_directory(&self, pin_data: PinByFile) -> Result<PinnedObject, ApiError> {
+        self.pin_file(pin_data).await
     }
 
     async fn unpin(&self, data: UnPin) -> Result<(), ApiError> {
         Err(ApiError::GenericError(format!("UnPin Cid feature not Implemented for Web3Storage, CID: {}", &data.cid)))
     }
-}
\ No newline at end of file
+
+    /// Follows the `Link` header of `/user/uploads` from the newest upload back, uploads carry
+    /// a name but no tags
+    async fn list_pins(&self, filter: PinFilter) -> Result<Vec<PinRecord>, ApiError> {
+        let mut url = format!("{}/user/uploads?size={}", &self.api_url, UPLOADS_PAGE_SIZE);
+        if let Some(before) = filter.before.as_deref() {
+            url = format!("{}&before={}", url, start_of_day(before));
+        }
+
+        let mut records = vec![];
+        loop {
+            let response = self.retry.send(&self.name, None, || Ok(self.client.get(url.as_str()))).await?;
+            let next = next_page(&response);
+            let uploads = self.parse_result::<Vec<Web3StorageUpload>>(response).await?;
+            // pages go from the newest upload to the oldest, so nothing after one older than `after` matches
+            let passed_after = match (filter.after.as_deref(), uploads.last().and_then(|upload| upload.created.as_deref())) {
+                (Some(after), Some(oldest)) => compare_timestamps(oldest, after).is_lt(),
+                _ => false,
+            };
+            let fetched = uploads.len();
+            records.extend(uploads.into_iter().map(PinRecord::from).filter(|record| filter.matches(record)));
+            match next {
+                Some(next) if fetched > 0 && !passed_after => url = format!("{}{}", &self.api_url, next),
+                _ => return Ok(records),
+            }
+        }
+    }
+}
+
+/// Path of the next page from a `Link: </user/uploads?...>; rel="next"` header
+fn next_page(response: &Response) -> Option<String> {
+    let link = response.headers().get(LINK)?.to_str().ok()?;
+    link.split(',')
+        .find(|link| link.contains("rel=\"next\""))
+        .and_then(|link| link.split_once('<'))
+        .and_then(|(_, rest)| rest.split_once('>'))
+        .map(|(path, _)| path.trim().to_string())
+}
+
+/// Names the upload through the `X-Name` header, percent encoded as web3.storage expects
+fn with_name(request: RequestBuilder, metadata: &PinMetadata) -> RequestBuilder {
+    match metadata.name.as_deref() {
+        Some(name) => {
+            let encoded = name.bytes()
+                .map(|byte| match byte {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                    _ => format!("%{:02X}", byte),
+                })
+                .collect::<String>();
+            request.header("X-Name", encoded)
+        }
+        None => request,
+    }
+}
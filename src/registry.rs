@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::credentials::CredentialResolver;
+use crate::data::SafeStorage;
+use crate::errors::{ApiError, Error};
+use crate::providers::kubo::KuboProvider;
+use crate::providers::memory::MemoryProvider;
+use crate::providers::pinata::PinataProvider;
+use crate::providers::pinning_service::PinningServiceProvider;
+use crate::providers::s3_compatible::S3CompatibleProvider;
+use crate::providers::web3_storage::Web3StorageProvider;
+use crate::retry::RetryPolicy;
+use crate::unixfs::DagOptions;
+
+/// Configuration values handed to a provider factory, keyed by `ConfigField::name`
+pub type ProviderConfig = HashMap<String, String>;
+
+/// Creates a provider from its configuration
+pub type ProviderFactory = Box<dyn Fn(&ProviderConfig) -> Result<SafeStorage, ApiError> + Send + Sync>;
+
+/// One configuration value a provider reads when it is created
+#[derive(Clone, Debug)]
+pub struct ConfigField {
+    pub name: String,
+    /// Env variable the provider falls back to when the value is not configured. Secrets are
+    /// looked up in the order of `CredentialResolver`, so a set env variable wins over them
+    pub env: Option<String>,
+    pub required: bool,
+    /// Credentials and other values that must never be printed
+    pub secret: bool,
+    pub description: String,
+}
+
+impl ConfigField {
+    pub fn required<S: Into<String>>(name: S, description: S) -> Self {
+        ConfigField { name: name.into(), env: None, required: true, secret: false, description: description.into() }
+    }
+
+    pub fn optional<S: Into<String>>(name: S, description: S) -> Self {
+        ConfigField { required: false, ..ConfigField::required(name, description) }
+    }
+
+    pub fn env<S: Into<String>>(mut self, env: S) -> Self {
+        self.env = Some(env.into());
+        self
+    }
+
+    pub fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    /// Whether the value is configured or available from the env. Secrets are looked up by the
+    /// provider through a `CredentialResolver`, which also asks the credential helper
+    fn is_set(&self, config: &ProviderConfig) -> bool {
+        config.contains_key(&self.name) || self.env.as_ref().map(|env| std::env::var(env).is_ok()).unwrap_or(false)
+    }
+}
+
+struct ProviderEntry {
+    name: String,
+    schema: Vec<ConfigField>,
+    factory: ProviderFactory,
+}
+
+/// Maps provider names to the factories that create them, so the CLI and library users can
+/// pick providers by name and plug in their own `StorageProvider` implementations
+pub struct ProviderRegistry {
+    providers: Vec<ProviderEntry>,
+}
+
+impl ProviderRegistry {
+    /// Creates a registry without any provider
+    pub fn new() -> Self {
+        ProviderRegistry { providers: vec![] }
+    }
+
+    /// Creates a registry holding every provider patter ships with
+    pub fn with_defaults() -> Self {
+        let mut registry = ProviderRegistry::new();
+        registry.register("pinata", vec![
+            ConfigField::optional("jwt", "Pinata jwt, used instead of the api key pair").env("PINATA_JWT").secret(),
+            ConfigField::optional("api_key", "Pinata api key").env("PINATA_API_KEY").secret(),
+            ConfigField::optional("secret_api_key", "Pinata secret api key").env("PINATA_SECRET_API_KEY").secret(),
+            ConfigField::optional("api_url", "Base url of the Pinata api"),
+            ConfigField::optional("max_attempts", "Attempts made for each request before giving up"),
+        ], |config| {
+            let mut provider = PinataProvider::with_resolver(None, None, &resolver("pinata", config))?
+                .with_retry(retry_policy(config)?);
+            if let Some(api_url) = value(config, "api_url") {
+                provider.api_url = api_url;
+            }
+            Ok(Box::new(provider) as SafeStorage)
+        });
+
+        registry.register("web3", vec![
+            ConfigField::required("token", "Web3Storage api token").env("WEB3STORAGE_API_TOKEN").secret(),
+            ConfigField::optional("shard_size", "Uploads above this many bytes are sent as car shards"),
+            ConfigField::optional("api_url", "Base url of the Web3Storage api"),
+            ConfigField::optional("max_attempts", "Attempts made for each request before giving up"),
+        ], |config| {
+            let mut provider = Web3StorageProvider::with_resolver(None, &resolver("web3", config))?
+                .with_retry(retry_policy(config)?);
+            if let Some(api_url) = value(config, "api_url") {
+                provider.api_url = api_url;
+            }
+            if let Some(shard_size) = number(config, "shard_size")? {
+                provider = provider.with_shard_size(shard_size);
+            }
+            Ok(Box::new(provider) as SafeStorage)
+        });
+
+        registry.register("pinning_service", vec![
+            ConfigField::required("endpoint", "Base url of the pinning service api").env("PINNING_SERVICE_ENDPOINT"),
+            ConfigField::required("token", "Pinning service access token").env("PINNING_SERVICE_TOKEN").secret(),
+            ConfigField::optional("max_attempts", "Attempts made for each request before giving up"),
+        ], |config| {
+            let provider = PinningServiceProvider::with_resolver(value(config, "endpoint"), None, &resolver("pinning_service", config))?
+                .with_retry(retry_policy(config)?);
+            Ok(Box::new(provider) as SafeStorage)
+        });
+
+        registry.register("kubo", vec![
+            ConfigField::optional("api_url", "Address of the Kubo rpc api").env("KUBO_API_URL"),
+            ConfigField::optional("max_attempts", "Attempts made for each request before giving up"),
+        ], |config| {
+            let provider = KuboProvider::new(value(config, "api_url"))
+                .map_err(into_api_error)?
+                .with_retry(retry_policy(config)?);
+            Ok(Box::new(provider) as SafeStorage)
+        });
+
+        registry.register("s3", vec![
+            ConfigField::required("endpoint", "S3 endpoint url").env("S3_ENDPOINT"),
+            ConfigField::required("bucket", "Bucket objects are uploaded to").env("S3_BUCKET"),
+            ConfigField::required("access_key_id", "S3 access key id").env("S3_ACCESS_KEY_ID").secret(),
+            ConfigField::required("secret_access_key", "S3 secret access key").env("S3_SECRET_ACCESS_KEY").secret(),
+            ConfigField::optional("region", "Region used to sign requests").env("S3_REGION"),
+            ConfigField::optional("part_size", "Multipart upload part size in bytes"),
+            ConfigField::optional("max_attempts", "Attempts made for each request before giving up"),
+        ], |config| {
+            let mut provider = S3CompatibleProvider::with_resolver(
+                value(config, "endpoint"),
+                value(config, "bucket"),
+                None,
+                None,
+                &resolver("s3", config),
+            )?.with_retry(retry_policy(config)?);
+            if let Some(region) = value(config, "region") {
+                provider = provider.with_region(region);
+            }
+            if let Some(part_size) = number(config, "part_size")? {
+                provider = provider.with_part_size(part_size);
+            }
+            Ok(Box::new(provider) as SafeStorage)
+        });
+
+        registry.register("memory", vec![
+            ConfigField::optional("cid_version", "0 for Pinata style cids, 1 for Web3Storage style cids"),
+        ], |config| {
+            let options = match value(config, "cid_version").as_deref() {
+                None | Some("0") => DagOptions::v0(),
+                Some("1") => DagOptions::v1(),
+                Some(version) => return Err(ApiError::GenericError(format!("Unsupported cid_version {}", version))),
+            };
+            Ok(Box::new(MemoryProvider::with_options(options)) as SafeStorage)
+        });
+
+        registry
+    }
+
+    /// Registers a provider, replacing any provider already registered under `name`
+    pub fn register<S, F>(&mut self, name: S, schema: Vec<ConfigField>, factory: F)
+        where S: Into<String>, F: Fn(&ProviderConfig) -> Result<SafeStorage, ApiError> + Send + Sync + 'static
+    {
+        let name = name.into();
+        self.providers.retain(|entry| entry.name != name);
+        self.providers.push(ProviderEntry { name, schema, factory: Box::new(factory) });
+    }
+
+    /// Names of the registered providers, in registration order
+    pub fn names(&self) -> Vec<String> {
+        self.providers.iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    pub fn schema(&self, name: &str) -> Option<&[ConfigField]> {
+        self.entry(name).ok().map(|entry| entry.schema.as_slice())
+    }
+
+    /// Creates the provider registered as `name`, checking its required configuration first
+    pub fn create(&self, name: &str, config: &ProviderConfig) -> Result<SafeStorage, ApiError> {
+        let entry = self.entry(name)?;
+        if let Some(field) = entry.schema.iter().find(|field| field.required && !field.secret && !field.is_set(config)) {
+            return Err(ApiError::MissingConfig { provider: entry.name.clone(), field: field.name.clone(), env: field.env.clone().unwrap_or_default() });
+        }
+        for field in entry.schema.iter().filter(|field| config.contains_key(&field.name)) {
+            if let Some(env) = field.env.as_ref().filter(|env| std::env::var(env).is_ok()) {
+                if field.secret {
+                    println!("[{}] {} is set and is used instead of the configured {}", entry.name, env, field.name);
+                } else {
+                    println!("[{}] {} is set but the configured {} is used instead", entry.name, env, field.name);
+                }
+            }
+        }
+        (entry.factory)(config)
+    }
+
+    /// Creates every provider of a comma separated list such as `pinata,web3`
+    pub fn create_all(&self, names: &str, configs: &HashMap<String, ProviderConfig>) -> Result<Vec<SafeStorage>, ApiError> {
+        let empty = ProviderConfig::new();
+        names.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| self.create(name, configs.get(name).unwrap_or(&empty)))
+            .collect()
+    }
+
+    fn entry(&self, name: &str) -> Result<&ProviderEntry, ApiError> {
+        self.providers.iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| ApiError::UnknownProvider { name: name.to_string(), valid: self.names().join(", ") })
+    }
+}
+
+impl Default for ProviderRegistry {
+    fn default() -> Self {
+        ProviderRegistry::with_defaults()
+    }
+}
+
+impl fmt::Debug for ProviderRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderRegistry").field("providers", &self.names()).finish()
+    }
+}
+
+fn value(config: &ProviderConfig, name: &str) -> Option<String> {
+    config.get(name).cloned()
+}
+
+fn number(config: &ProviderConfig, name: &str) -> Result<Option<u64>, ApiError> {
+    match config.get(name) {
+        Some(number) => number.parse::<u64>()
+            .map(Some)
+            .map_err(|_| ApiError::GenericError(format!("{} must be a number, got {}", name, number))),
+        None => Ok(None),
+    }
+}
+
+fn retry_policy(config: &ProviderConfig) -> Result<RetryPolicy, ApiError> {
+    let policy = RetryPolicy::default();
+    Ok(match number(config, "max_attempts")? {
+        Some(max_attempts) => policy.with_max_attempts(max_attempts.min(u32::MAX as u64) as u32),
+        None => policy,
+    })
+}
+
+fn resolver(provider: &str, config: &ProviderConfig) -> CredentialResolver {
+    CredentialResolver::new(provider, config.clone())
+}
+
+fn into_api_error(error: Error) -> ApiError {
+    ApiError::GenericError(error.to_string())
+}
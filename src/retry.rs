@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use reqwest::header::RETRY_AFTER;
+use crate::errors::ApiError;
+use crate::progress::ProgressReporter;
+use crate::utils::parse_http_date;
+
+tokio::task_local! {
+    /// Retries made by the operation running on the current task, see `count_retries`
+    static RETRIES: Arc<AtomicU32>;
+}
+
+/// Runs `operation` counting every retry its requests make into `retries`
+pub(crate) async fn count_retries<F: std::future::Future>(retries: Arc<AtomicU32>, operation: F) -> F::Output {
+    RETRIES.scope(retries, operation).await
+}
+
+/// How often and how patiently a provider retries requests that failed for a passing reason:
+/// connection errors, timeouts, 5xx responses and 429 responses
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts made in total, 1 disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+    /// Longest delay between two attempts. A server asking for a longer `Retry-After` is
+    /// not retried sooner than it asked, the request gives up instead
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(500), max_delay: Duration::from_secs(30) }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy making a single attempt
+    pub fn none() -> Self {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sends the request built by `request`, building it again for every attempt so upload
+    /// bodies are streamed from the start of their files each time. Bytes reported to
+    /// `progress` by a failed attempt are taken back before the next one.
+    ///
+    /// Returns the response of the last attempt even when it is an error response, so the
+    /// provider can still parse the error body
+    pub async fn send<F>(&self, provider: &str, progress: Option<&ProgressReporter>, request: F) -> Result<Response, ApiError>
+        where F: FnMut() -> Result<RequestBuilder, ApiError>
+    {
+        self.send_when(provider, progress, is_transient, request).await
+    }
+
+    /// `send` retrying only the responses whose status `transient` accepts, for apis that
+    /// answer lasting errors with a status other apis only use for passing ones
+    pub async fn send_when<F>(&self, provider: &str, progress: Option<&ProgressReporter>, transient: fn(StatusCode) -> bool, mut request: F) -> Result<Response, ApiError>
+        where F: FnMut() -> Result<RequestBuilder, ApiError>
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let checkpoint = progress.map(|progress| progress.total_sent());
+        let mut attempt = 1;
+        loop {
+            let result = request()?.send().await;
+            let (reason, retry_after) = match &result {
+                Ok(response) if !transient(response.status()) => return result.map_err(ApiError::from),
+                Ok(response) => (format!("HTTP {}", response.status()), retry_after(response)),
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => (e.to_string(), None),
+                Err(_) => return result.map_err(ApiError::from),
+            };
+
+            if attempt >= max_attempts {
+                println!("[{}] attempt {}/{} failed: {}, giving up", provider, attempt, max_attempts, reason);
+                return result.map_err(ApiError::from);
+            }
+            if let Some(retry_after) = retry_after.filter(|retry_after| *retry_after > self.max_delay) {
+                println!("[{}] attempt {}/{} failed: {}, server asks to retry in {:?}, giving up", provider, attempt, max_attempts, reason, retry_after);
+                return result.map_err(ApiError::from);
+            }
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            println!("[{}] attempt {}/{} failed: {}, retrying in {:?}", provider, attempt, max_attempts, reason, delay);
+            tokio::time::sleep(delay).await;
+            // only counted when the operation is run by `count_retries`
+            let _ = RETRIES.try_with(|retries| retries.fetch_add(1, Ordering::Relaxed));
+            if let (Some(progress), Some(checkpoint)) = (progress, checkpoint) {
+                progress.rewind(checkpoint);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Exponential backoff with jitter, the delay is picked between half and all of
+    /// `base_delay * 2^(attempt - 1)` so clients failing together do not retry together
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1)).min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen::<f64>() / 2.0)
+    }
+}
+
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Delay asked for by a `Retry-After` header, given either in seconds or as an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+/// Delay a `Retry-After` value asks for as of `now`, a date already passed asks for none
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = parse_http_date(value)?;
+    let now = now.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() as i64).unwrap_or(0);
+    Some(Duration::from_secs(date.saturating_sub(now).max(0) as u64))
+}
@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::cid::{Cid, CidVersion, DAG_PB, RAW};
+use crate::errors::ApiError;
+pub use pb::Link;
+use pb::{encode_node, DataType, UnixFsData};
+
+mod pb;
+
+/// Chunk size used by go-ipfs, Pinata and Web3Storage when none is given
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum number of children of an intermediate file node in the balanced layout
+pub const DEFAULT_MAX_LINKS: usize = 174;
+
+/// Receives every block of a dag as it is built, children before their parents
+pub type BlockSink<'a> = dyn FnMut(&Cid, &[u8]) -> Result<(), ApiError> + 'a;
+
+#[derive(Clone, Debug)]
+pub struct DagOptions {
+    pub cid_version: CidVersion,
+    /// Store file chunks as raw blocks instead of wrapping them in dag-pb nodes
+    pub raw_leaves: bool,
+    pub chunk_size: usize,
+    pub max_links: usize,
+    /// Wrap a single file or directory in a directory too, so it keeps its name under the root
+    pub wrap_with_directory: bool,
+}
+
+impl DagOptions {
+    /// Options matching what Pinata produces: CIDv0 with dag-pb leaves
+    pub fn v0() -> Self {
+        DagOptions { cid_version: CidVersion::V0, raw_leaves: false, chunk_size: DEFAULT_CHUNK_SIZE, max_links: DEFAULT_MAX_LINKS, wrap_with_directory: false }
+    }
+
+    /// Options matching what Web3Storage produces: CIDv1 with raw leaves
+    pub fn v1() -> Self {
+        DagOptions { cid_version: CidVersion::V1, raw_leaves: true, chunk_size: DEFAULT_CHUNK_SIZE, max_links: DEFAULT_MAX_LINKS, wrap_with_directory: false }
+    }
+}
+
+/// Builds the UnixFS merkle dag for the same files `utils::transform_file_to_form` uploads
+pub struct DagBuilder {
+    options: DagOptions,
+}
+
+impl DagBuilder {
+    pub fn new(options: DagOptions) -> Self {
+        DagBuilder { options }
+    }
+
+    /// Builds the dag for `files`, handing each block to `sink`, and returns the link to its root.
+    ///
+    /// A single file or directory becomes the root itself unless `wrap_with_directory` is set,
+    /// several paths are wrapped in a directory named after each entry.
+    pub fn build(&self, files: &[String], sink: &mut BlockSink) -> Result<Link, ApiError> {
+        let mut links = vec![];
+        for file in files.iter() {
+            let path = PathBuf::from(file);
+            let name = entry_name(&path)?;
+            if let Some(mut link) = self.add_path(&path, sink)? {
+                link.name = name;
+                links.push(link);
+            }
+        }
+
+        match links.len() {
+            0 => Err(ApiError::GenericError(format!("No files to build a dag from in {:?}", files))),
+            1 if !self.options.wrap_with_directory => Ok(links.remove(0)),
+            _ => self.add_directory_node(links, sink),
+        }
+    }
+
+    /// Computes the root cid of `files` without keeping any of the blocks
+    pub fn root_cid(&self, files: &[String]) -> Result<Cid, ApiError> {
+        let link = self.build(files, &mut |_, _| Ok(()))?;
+        Ok(link.cid)
+    }
+
+    fn add_path(&self, path: &Path, sink: &mut BlockSink) -> Result<Option<Link>, ApiError> {
+        if path.is_dir() {
+            self.add_directory(path, sink)
+        } else {
+            self.add_file(path, sink).map(Some)
+        }
+    }
+
+    /// Directories without any file below them are skipped, as the multipart upload never sends them
+    fn add_directory(&self, path: &Path, sink: &mut BlockSink) -> Result<Option<Link>, ApiError> {
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, io::Error>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut links = vec![];
+        for entry in entries {
+            if let Some(mut link) = self.add_path(&entry.path(), sink)? {
+                link.name = entry.file_name().to_string_lossy().to_string();
+                links.push(link);
+            }
+        }
+
+        if links.is_empty() {
+            return Ok(None);
+        }
+        self.add_directory_node(links, sink).map(Some)
+    }
+
+    fn add_directory_node(&self, mut links: Vec<Link>, sink: &mut BlockSink) -> Result<Link, ApiError> {
+        links.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+        let data = UnixFsData { kind: Some(DataType::Directory), ..Default::default() }.encode();
+        let block = encode_node(&links, &data);
+
+        let tsize = block.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>();
+        let cid = Cid::for_block(self.options.cid_version, DAG_PB, &block);
+        sink(&cid, &block)?;
+        Ok(Link { cid, name: String::new(), tsize })
+    }
+
+    fn add_file(&self, path: &Path, sink: &mut BlockSink) -> Result<Link, ApiError> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; self.options.chunk_size];
+
+        // every node is paired with the number of file bytes below it
+        let mut level: Vec<(Link, u64)> = vec![];
+        loop {
+            let read = read_chunk(&mut file, &mut buf)?;
+            if read == 0 && !level.is_empty() {
+                break;
+            }
+            let kind = if level.is_empty() { DataType::File } else { DataType::Raw };
+            level.push(self.add_leaf(&buf[..read], kind, sink)?);
+            if read < buf.len() {
+                break;
+            }
+        }
+
+        while level.len() > 1 {
+            level = level
+                .chunks(self.options.max_links)
+                .map(|children| self.add_file_node(children, sink))
+                .collect::<Result<Vec<_>, ApiError>>()?;
+        }
+
+        Ok(level.remove(0).0)
+    }
+
+    /// go-ipfs only types the first leaf as a file, the ones after it are typed raw
+    fn add_leaf(&self, chunk: &[u8], kind: DataType, sink: &mut BlockSink) -> Result<(Link, u64), ApiError> {
+        let (cid, block) = if self.options.raw_leaves {
+            (Cid::for_block(CidVersion::V1, RAW, chunk), chunk.to_vec())
+        } else {
+            let data = UnixFsData {
+                kind: Some(kind),
+                data: if chunk.is_empty() { None } else { Some(chunk) },
+                file_size: Some(chunk.len() as u64),
+                ..Default::default()
+            }.encode();
+            let block = encode_node(&[], &data);
+            (Cid::for_block(self.options.cid_version, DAG_PB, &block), block)
+        };
+
+        sink(&cid, &block)?;
+        Ok((Link { cid, name: String::new(), tsize: block.len() as u64 }, chunk.len() as u64))
+    }
+
+    fn add_file_node(&self, children: &[(Link, u64)], sink: &mut BlockSink) -> Result<(Link, u64), ApiError> {
+        let block_sizes = children.iter().map(|(_, size)| *size).collect::<Vec<u64>>();
+        let file_size = block_sizes.iter().sum::<u64>();
+        let data = UnixFsData {
+            kind: Some(DataType::File),
+            file_size: Some(file_size),
+            block_sizes,
+            ..Default::default()
+        }.encode();
+
+        let links = children.iter().map(|(link, _)| link.clone()).collect::<Vec<Link>>();
+        let block = encode_node(&links, &data);
+        let tsize = block.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>();
+        let cid = Cid::for_block(self.options.cid_version, DAG_PB, &block);
+
+        sink(&cid, &block)?;
+        Ok((Link { cid, name: String::new(), tsize }, file_size))
+    }
+}
+
+/// The cids each kind of provider is expected to answer with for an upload
+#[derive(Clone, Debug)]
+pub struct ExpectedCids {
+    pub v0: Cid,
+    pub v1: Cid,
+}
+
+impl ExpectedCids {
+    pub fn compute(files: &[String]) -> Result<ExpectedCids, ApiError> {
+        ExpectedCids::compute_wrapped(files, false)
+    }
+
+    /// Expected cids of an upload wrapped in a directory when `wrap_with_directory` is set
+    pub fn compute_wrapped(files: &[String], wrap_with_directory: bool) -> Result<ExpectedCids, ApiError> {
+        Ok(ExpectedCids {
+            v0: DagBuilder::new(DagOptions { wrap_with_directory, ..DagOptions::v0() }).root_cid(files)?,
+            v1: DagBuilder::new(DagOptions { wrap_with_directory, ..DagOptions::v1() }).root_cid(files)?,
+        })
+    }
+
+    /// Checks a provider's answer against the expected cids, whatever version or base it is written in
+    pub fn matches(&self, ipfs_hash: &str) -> bool {
+        match ipfs_hash.parse::<Cid>() {
+            Ok(cid) => cid == self.v0 || cid == self.v1,
+            Err(_) => false,
+        }
+    }
+}
+
+fn entry_name(path: &Path) -> Result<String, ApiError> {
+    let path = path.canonicalize()?;
+    match path.file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(ApiError::GenericError(format!("Could not get file name of {:?}", path))),
+    }
+}
+
+/// Fills `buf` as far as the file allows, returning the number of bytes read
+fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = file.read(&mut buf[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
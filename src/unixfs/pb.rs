@@ -0,0 +1,78 @@
+use crate::cid::{write_varint, Cid};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_BYTES: u64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DataType {
+    Raw = 0,
+    Directory = 1,
+    File = 2,
+}
+
+/// UnixFS `Data` message stored in the data field of a dag-pb node
+#[derive(Debug, Default)]
+pub(crate) struct UnixFsData<'a> {
+    pub kind: Option<DataType>,
+    pub data: Option<&'a [u8]>,
+    pub file_size: Option<u64>,
+    pub block_sizes: Vec<u64>,
+}
+
+impl<'a> UnixFsData<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        if let Some(kind) = self.kind {
+            write_key(&mut buf, 1, WIRE_VARINT);
+            write_varint(&mut buf, kind as u64);
+        }
+        if let Some(data) = self.data {
+            write_bytes(&mut buf, 2, data);
+        }
+        if let Some(file_size) = self.file_size {
+            write_key(&mut buf, 3, WIRE_VARINT);
+            write_varint(&mut buf, file_size);
+        }
+        // go-ipfs writes blocksizes unpacked, so the cids only match when we do too
+        for size in self.block_sizes.iter() {
+            write_key(&mut buf, 4, WIRE_VARINT);
+            write_varint(&mut buf, *size);
+        }
+        buf
+    }
+}
+
+/// A named link from a dag-pb node to one of its children
+#[derive(Clone, Debug)]
+pub struct Link {
+    pub cid: Cid,
+    pub name: String,
+    /// Cumulative size of the child block and everything below it
+    pub tsize: u64,
+}
+
+/// Encodes a dag-pb `PBNode`. Links are written before data as the dag-pb spec requires
+pub(crate) fn encode_node(links: &[Link], data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    for link in links {
+        let mut encoded = vec![];
+        write_bytes(&mut encoded, 1, &link.cid.to_bytes());
+        write_bytes(&mut encoded, 2, link.name.as_bytes());
+        write_key(&mut encoded, 3, WIRE_VARINT);
+        write_varint(&mut encoded, link.tsize);
+
+        write_bytes(&mut buf, 2, &encoded);
+    }
+    write_bytes(&mut buf, 1, data);
+    buf
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, (field << 3) | wire_type);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(buf, field, WIRE_BYTES);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
@@ -1,9 +1,19 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures_util::{stream, TryStreamExt};
+use reqwest::Body;
 use reqwest::multipart::{Form, Part};
+use tokio_util::io::ReaderStream;
 use walkdir::WalkDir;
 use crate::api::data::PinByFile;
 use crate::errors::ApiError;
+use crate::progress::ProgressReporter;
+
+/// Error for credentials a provider answered 401 to
+pub(crate) fn rejected_credential(provider: &str, name: &str) -> ApiError {
+    ApiError::InvalidCredential { provider: provider.to_string(), name: name.to_string(), reason: "the provider rejected it".to_string() }
+}
 
 pub fn trim_newline(s: &mut String) -> String {
     if s.ends_with('\n') {
@@ -15,6 +25,201 @@ pub fn trim_newline(s: &mut String) -> String {
     s.to_owned()
 }
 
+/// Splits a time into its UTC year, month, day, hour, minute and second
+pub fn utc_parts(time: SystemTime) -> (i64, i64, i64, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Year, month and day of a count of days since 1970-01-01,
+/// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Days since 1970-01-01 of a date, the inverse of `civil_from_days`
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Microseconds since the epoch of a `YYYY-MM-DD` date, taken as the start of the day in UTC,
+/// or of an RFC 3339 timestamp with any fraction and offset. A timestamp without an offset is
+/// taken to be UTC. `None` when `text` is neither
+pub fn parse_timestamp(text: &str) -> Option<i64> {
+    fn number(text: &str, range: std::ops::RangeInclusive<i64>) -> Option<i64> {
+        let value = text.parse::<i64>().ok().filter(|value| range.contains(value))?;
+        text.bytes().all(|byte| byte.is_ascii_digit()).then_some(value)
+    }
+
+    let text = text.trim();
+    let date = text.get(..10)?;
+    if date.as_bytes()[4] != b'-' || date.as_bytes()[7] != b'-' {
+        return None;
+    }
+    let days = days_from_civil(number(&date[..4], 0..=9999)?, number(&date[5..7], 1..=12)?, number(&date[8..10], 1..=31)?);
+    let rest = &text[10..];
+    if rest.is_empty() {
+        return Some(days * 86_400_000_000);
+    }
+
+    let time = rest.strip_prefix(['T', 't', ' '])?;
+    let clock = time.get(..8)?;
+    if clock.as_bytes()[2] != b':' || clock.as_bytes()[5] != b':' {
+        return None;
+    }
+    let seconds = number(&clock[..2], 0..=23)? * 3600 + number(&clock[3..5], 0..=59)? * 60 + number(&clock[6..8], 0..=60)?;
+    let mut rest = &time[8..];
+
+    let mut micros = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(|byte| byte.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        micros = format!("{:0<6}", &fraction[..digits.min(6)]).parse::<i64>().ok()?;
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = rest[1..].replace(':', "");
+            if offset.len() != 4 || !offset.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            sign * (number(&offset[..2], 0..=23)? * 3600 + number(&offset[2..], 0..=59)? * 60)
+        }
+    };
+    Some((days * 86400 + seconds - offset) * 1_000_000 + micros)
+}
+
+/// Seconds since the epoch of an HTTP date in the IMF-fixdate form servers send,
+/// such as `Sun, 06 Nov 1994 08:49:37 GMT`. `None` for any other form
+pub fn parse_http_date(text: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (_, date) = text.trim().split_once(", ")?;
+    let parts: Vec<&str> = date.split(' ').collect();
+    if parts.len() != 5 || parts[4] != "GMT" || parts[0].len() != 2 || parts[2].len() != 4 {
+        return None;
+    }
+    let month = MONTHS.iter().position(|month| *month == parts[1])? as i64 + 1;
+    let clock: Vec<i64> = parts[3].split(':').map(|part| part.parse::<i64>().ok()).collect::<Option<_>>()?;
+    if clock.len() != 3 || clock[0] > 23 || clock[1] > 59 || clock[2] > 60 {
+        return None;
+    }
+    let days = days_from_civil(parts[2].parse().ok()?, month, parts[0].parse().ok().filter(|day| (1..=31).contains(day))?);
+    Some(days * 86400 + clock[0] * 3600 + clock[1] * 60 + clock[2])
+}
+
+/// RFC 3339 UTC timestamp of microseconds since the epoch, with a fraction only when there is one
+pub fn format_timestamp(micros: i64) -> String {
+    let (secs, fraction) = (micros.div_euclid(1_000_000), micros.rem_euclid(1_000_000));
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    let time = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60);
+    if fraction == 0 { format!("{}Z", time) } else { format!("{}.{:06}Z", time, fraction) }
+}
+
+/// Orders two dates or timestamps by the instant they stand for, whatever their offsets.
+/// Text that does not parse is compared as it is
+pub fn compare_timestamps(a: &str, b: &str) -> std::cmp::Ordering {
+    match (parse_timestamp(a), parse_timestamp(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// RFC 3339 timestamp of the current time, in the format Pinata returns
+pub fn utc_timestamp() -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(SystemTime::now());
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// UTC timestamp of a `YYYY-MM-DD` date or a timestamp with any offset, for apis that only
+/// take full UTC timestamps. Text that does not parse is returned as it is
+pub fn start_of_day(date: &str) -> String {
+    parse_timestamp(date).map(format_timestamp).unwrap_or_else(|| date.to_string())
+}
+
+/// Total size in bytes of the files `transform_file_to_form` would upload
+pub fn total_file_size(files: &[String]) -> Result<u64, ApiError> {
+    let mut total = 0;
+    for file in files.iter() {
+        for entry_result in WalkDir::new(file) {
+            let entry = entry_result?;
+            if entry.path().is_file() {
+                total += entry.metadata()?.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Creates a fresh directory under the system temp dir, the caller removes it when done
+pub fn create_temp_dir(prefix: &str) -> Result<PathBuf, ApiError> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let dir = std::env::temp_dir().join(format!("patter-{}-{}-{}", prefix, std::process::id(), nanos));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Read size of the streams `file_body` sends files with
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Request body that reads `path` from disk while it is sent. The file is only opened once
+/// the body is first polled, so a form holding many files keeps a single one open at a time
+pub fn file_body(path: &Path, progress: Option<&ProgressReporter>) -> Result<Body, ApiError> {
+    let file_size = fs::metadata(path)?.len();
+    let name = path.to_string_lossy().to_string();
+    let progress = progress.cloned();
+    let mut file_sent = 0;
+    let stream = stream::once(tokio::fs::File::open(path.to_path_buf()))
+        .map_ok(|file| ReaderStream::with_capacity(file, STREAM_CHUNK_SIZE))
+        .try_flatten()
+        .inspect_ok(move |chunk| {
+            file_sent += chunk.len() as u64;
+            if let Some(progress) = progress.as_ref() {
+                progress.sent(&name, file_sent, file_size, chunk.len() as u64);
+            }
+        });
+    Ok(Body::wrap_stream(stream))
+}
+
+/// Multipart part streaming `path` from disk, its length is known up front so the form
+/// can still be sent with a content-length
+pub fn file_part(path: &Path, progress: Option<&ProgressReporter>) -> Result<Part, ApiError> {
+    let length = fs::metadata(path)?.len();
+    Ok(Part::stream_with_length(file_body(path, progress)?, length))
+}
+
+/// Name of the last component of `path`, for paths such as `.` that have none the path itself
+fn display_name(path: &Path) -> String {
+    path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().to_string()
+}
+
+/// Multipart form of every file in `pin_data`, directories are walked and their files named
+/// relative to the directory. Files are streamed from disk as the form is sent
 pub fn transform_file_to_form(pin_data: &PinByFile) -> Result<Form, ApiError> {
     let mut form = Form::new();
     println!("File path {:?}", pin_data.files);
@@ -32,17 +237,16 @@ pub fn transform_file_to_form(pin_data: &PinByFile) -> Result<Form, ApiError> {
                 if path.is_dir() { continue }
 
                 let path_name = path.strip_prefix(base_path)?;
-                let part_file_name = format!("{}/{}", base_path.file_name().unwrap().to_str().unwrap(), path_name.to_str().unwrap());
+                let part_file_name = format!("{}/{}", display_name(base_path), path_name.to_string_lossy());
 
-                let part = Part::bytes(fs::read(path)?)
+                let part = file_part(path, pin_data.progress.as_ref())?
                     .file_name(part_file_name);
                 form = form.part("file", part);
             }
 
         } else {
-            let file_name = base_path.file_name().unwrap().to_str().unwrap();
-            let part = Part::bytes(fs::read(base_path)?);
-            form = form.part("file", part.file_name(String::from(file_name)));
+            let part = file_part(base_path, pin_data.progress.as_ref())?;
+            form = form.part("file", part.file_name(display_name(base_path)));
         }
     };
 
@@ -19,6 +19,110 @@ async fn pin_file_pinata() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[tokio::test]
+async fn pin_file_memory() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-f=./LICENSE").arg("-p=memory").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrhP"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_file_quorum() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    cmd.arg("-a=pin_file").arg("-f=./LICENSE").arg("-p=memory").arg("--quorum=memory").assert().success();
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-f=./LICENSE").arg("-p=memory").arg("--quorum=2").assert();
+    assert
+        .code(2)
+        .stdout(predicate::str::contains("1 of 1 providers succeeded but at least 2 providers had to"))
+        .stdout(predicate::str::contains("Memory Provider succeeded"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_hash_invalid_cid() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_hash").arg("-p=memory").arg("--hash=QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrh").assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Invalid cid"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_hash").arg("-p=memory").arg("--hash=QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrhP").arg("--cid-base=base32").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("pin hash bafybeic"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_hash_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_hash").arg("-p=memory").arg("--hash=QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrhP")
+        .arg("--name=backup").arg("--tag=env=prod").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("name: Some(\"backup\")"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_hash").arg("-p=memory").arg("--hash=QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrhP").arg("--tag=env").assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("Tag env is not written as key=value"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn pin_file_options() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-p=memory").arg("-f=./LICENSE").arg("--cid-version=1").arg("--wrap").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("bafybei"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-p=memory").arg("-f=./LICENSE").arg("--replicate=FRA1").assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("Replication FRA1 is not written as REGION=COUNT"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-p=memory").arg("-f=./LICENSE").arg("--provider-option=pinta.groupId=backups").arg("--provider-option=memory.region=eu").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Provider option pinta.groupId is ignored, pinta is not one of the selected providers"))
+        .stdout(predicate::str::contains("Provider option memory.region is ignored, memory takes no provider options"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_provider() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_file").arg("-f=./LICENSE").arg("-p=memory,nope").assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("Unknown provider nope, valid providers are: pinata, web3"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_file_path() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    cmd.arg("-a=cid").assert().failure().stdout(predicate::str::contains("Pass the file to cid with --file-path"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=pin_car").arg("-p=memory").assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("Pass the file to pin_car with --file-path"));
+    Ok(())
+}
+
+#[tokio::test]
+#[allow(clippy::suspicious_command_arg_space)]
 async fn fail_arg() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("patter")?;
     let assert = cmd.arg("-a pin_file").arg("-f=./test.json").arg("-p=web3").assert();
@@ -27,3 +131,63 @@ async fn fail_arg() -> Result<(), Box<dyn std::error::Error>> {
         .stderr(predicate::str::contains("--action pin_file"));
     Ok(())
 }
+
+#[tokio::test]
+async fn pin_file_profile() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("patter-profile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("patter.toml"), "[profiles.local]\nproviders = [\"memory\"]\n")?;
+    let license = std::fs::canonicalize("./LICENSE")?;
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.current_dir(&dir)
+        .arg("-a=pin_file")
+        .arg(format!("-f={}", license.display()))
+        .arg("--profile=local")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Using profile local"))
+        .stdout(predicate::str::contains("QmT5zMbasLqSDw4MnbZPmgWnnhGpf7BNs32keMYeL7vrhP"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn list_pins_filters() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=list").arg("-p=memory").arg("--name=backup").arg("--after=2024-01-01").arg("--status=pinned").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Memory Provider listed 0 pin(s)"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=list").arg("-p=memory").arg("--before=last-week").assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("last-week is not a YYYY-MM-DD date"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=list").arg("-p=memory").arg("--status=lost").assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Unknown pin status lost"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn list_pins_partial() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=list").arg("-p=memory,kubo").env("KUBO_API_URL", "http://127.0.0.1:9").assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Memory Provider listed 0 pin(s)"))
+        .stdout(predicate::str::contains("Kubo Provider failed"));
+
+    let mut cmd = Command::cargo_bin("patter")?;
+    let assert = cmd.arg("-a=list").arg("-p=memory,kubo").arg("--quorum=all").env("KUBO_API_URL", "http://127.0.0.1:9").assert();
+    assert
+        .failure()
+        .stdout(predicate::str::contains("Memory Provider listed 0 pin(s)"));
+    Ok(())
+}